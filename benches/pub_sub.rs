@@ -0,0 +1,121 @@
+use beaver::global_static::CONFIG;
+use beaver::server::Server;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::runtime::Runtime;
+
+// 多个发布者同时向同一个subject发布, 测试订阅列表在并发下的性能
+// 服务端使用Config.toml里面的地址, 运行之前要确认端口没有被占用
+const PUBLISHERS: usize = 4;
+const MESSAGES: usize = 100;
+const PAYLOAD_SIZE: usize = 128;
+
+// 每个订阅者收到的每条消息都是 MSG foo 1 128\r\n[payload]\r\n
+const MSG_SIZE: usize = b"MSG foo 1 128\r\n".len() + PAYLOAD_SIZE + 2;
+
+async fn connect(addr: &str) -> TcpStream {
+    loop {
+        if let Ok(stream) = TcpStream::connect(addr).await {
+            return stream;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+// 等待服务端处理完之前发送的数据
+async fn sync(stream: &mut TcpStream) {
+    stream.write_all(b"PING\r\n").await.unwrap();
+    let mut result: Vec<u8> = Vec::new();
+    let mut buff: Vec<u8> = vec![0; 1024];
+    while !result.ends_with(b"PONG\r\n") {
+        let size: usize = stream.read(&mut buff).await.unwrap();
+        result.extend_from_slice(&buff[..size]);
+    }
+}
+
+fn pub_sub(c: &mut Criterion) {
+    let runtime: Runtime = Runtime::new().unwrap();
+    let server = CONFIG.get_server();
+    let addr: String = format!("{}:{}", server.get_ip(), server.get_port());
+    runtime.spawn(async {
+        Server::new().unwrap().run().await.unwrap();
+    });
+
+    let mut message: Vec<u8> = format!("PUB foo {}\r\n", PAYLOAD_SIZE).into_bytes();
+    message.extend_from_slice(&[b'a'; PAYLOAD_SIZE]);
+    message.extend_from_slice(b"\r\n");
+    let batch: Vec<u8> = message.repeat(MESSAGES);
+
+    let mut group = c.benchmark_group("pub_sub");
+    group.sample_size(10);
+
+    for subscribers in [10, 100, 1000].iter() {
+        let (mut subs, mut pubs) = runtime.block_on(async {
+            let mut subs: Vec<TcpStream> = Vec::with_capacity(*subscribers);
+            for _ in 0..*subscribers {
+                let mut stream: TcpStream = connect(&addr).await;
+                stream.write_all(b"SUB foo 1\r\n").await.unwrap();
+                sync(&mut stream).await;
+                subs.push(stream);
+            }
+            let mut pubs: Vec<TcpStream> = Vec::with_capacity(PUBLISHERS);
+            for _ in 0..PUBLISHERS {
+                let mut stream: TcpStream = connect(&addr).await;
+                sync(&mut stream).await;
+                pubs.push(stream);
+            }
+            (subs, pubs)
+        });
+
+        group.bench_with_input(
+            BenchmarkId::from_parameter(subscribers),
+            subscribers,
+            |b, _| {
+                b.iter_custom(|iters| {
+                    runtime.block_on(async {
+                        let start: Instant = Instant::now();
+                        for _ in 0..iters {
+                            let readers: Vec<_> = subs
+                                .drain(..)
+                                .map(|mut stream| {
+                                    tokio::spawn(async move {
+                                        let mut buff: Vec<u8> = vec![0; PUBLISHERS * MESSAGES * MSG_SIZE];
+                                        stream.read_exact(&mut buff).await.unwrap();
+                                        stream
+                                    })
+                                })
+                                .collect();
+                            let writers: Vec<_> = pubs
+                                .drain(..)
+                                .map(|mut stream| {
+                                    let batch: Vec<u8> = batch.clone();
+                                    tokio::spawn(async move {
+                                        stream.write_all(&batch).await.unwrap();
+                                        stream
+                                    })
+                                })
+                                .collect();
+
+                            for writer in writers {
+                                pubs.push(writer.await.unwrap());
+                            }
+                            for reader in readers {
+                                subs.push(reader.await.unwrap());
+                            }
+                        }
+                        start.elapsed()
+                    })
+                });
+            },
+        );
+
+        drop(subs);
+        drop(pubs);
+    }
+    group.finish();
+}
+
+criterion_group!(benches, pub_sub);
+criterion_main!(benches);
@@ -1,9 +1,23 @@
 use serde_derive::Deserialize;
+use std::collections::HashMap;
 use std::fs::{File, OpenOptions};
 use std::io::{BufReader, Error as IoError, Read};
 use thiserror::Error;
 use toml::de::Error as TomlDeserializeError;
 
+// 和nats一样, 默认每2分钟ping一次, 最多允许2个ping没有收到回复
+const DEFAULT_PING_INTERVAL: u64 = 120;
+const DEFAULT_MAX_PINGS_OUTSTANDING: usize = 2;
+// 控制行(除了payload以外的那一行)的最大长度, 和nats的默认值一样
+pub(crate) const DEFAULT_MAX_CONTROL_LINE: usize = 4096;
+// 每个连接还没发送出去的数据最多64M, 写入socket最多等待10秒, 超过的话就是慢消费者
+const DEFAULT_MAX_PENDING: usize = 64 * 1024 * 1024;
+const DEFAULT_WRITE_TIMEOUT: u64 = 10;
+// TLS握手的超时时间, 和nats一样默认2秒
+const DEFAULT_TLS_TIMEOUT: f64 = 2.0;
+// 需要认证的时候, 连接之后必须在这个时间之内发送CONNECT
+const DEFAULT_AUTH_TIMEOUT: f64 = 2.0;
+
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("io error `{0}`")]
@@ -20,14 +34,23 @@ pub struct ServerConfig {
     ip: String,
     port: u16,
     version: String,
-    auth_required: bool,
-    ssl_required: bool,
     max_payload: usize,
-    read_timeout: Option<u64>,
+    max_control_line: Option<usize>,
+    max_pending: Option<usize>,
+    // 单位是秒, 0表示写入不限时间
     write_timeout: Option<u64>,
-    connect_timeout: Option<u64>,
+    // 单位是秒, 0表示不ping客户端
+    ping_interval: Option<u64>,
+    max_pings_outstanding: Option<usize>,
     proto: usize,
     io_buffer_size: usize,
+    // 没有配置的话不使用TLS
+    tls: Option<TlsConfig>,
+    authorization: Option<AuthorizationConfig>,
+    // 配置了operator的话, 客户端可以用账户签发的JWT认证
+    operator: Option<OperatorConfig>,
+    // 每个账户有自己的用户和subject空间, 不同账户之间的消息互相看不到
+    accounts: Option<HashMap<String, AccountConfig>>,
 }
 
 impl ServerConfig {
@@ -51,16 +74,29 @@ impl ServerConfig {
         &self.version
     }
 
-    pub fn get_auth_required(&self) -> bool {
-        self.auth_required
+    pub fn get_max_payload(&self) -> usize {
+        self.max_payload
     }
 
-    pub fn get_ssl_required(&self) -> bool {
-        self.ssl_required
+    pub fn get_max_control_line(&self) -> usize {
+        self.max_control_line.unwrap_or(DEFAULT_MAX_CONTROL_LINE)
     }
 
-    pub fn get_max_payload(&self) -> usize {
-        self.max_payload
+    pub fn get_max_pending(&self) -> usize {
+        self.max_pending.unwrap_or(DEFAULT_MAX_PENDING)
+    }
+
+    pub fn get_write_timeout(&self) -> u64 {
+        self.write_timeout.unwrap_or(DEFAULT_WRITE_TIMEOUT)
+    }
+
+    pub fn get_ping_interval(&self) -> u64 {
+        self.ping_interval.unwrap_or(DEFAULT_PING_INTERVAL)
+    }
+
+    pub fn get_max_pings_outstanding(&self) -> usize {
+        self.max_pings_outstanding
+            .unwrap_or(DEFAULT_MAX_PINGS_OUTSTANDING)
     }
 
     pub fn get_proto(&self) -> usize {
@@ -70,6 +106,362 @@ impl ServerConfig {
     pub fn get_io_buffer_size(&self) -> usize {
         self.io_buffer_size
     }
+
+    pub fn get_tls(&self) -> Option<&TlsConfig> {
+        self.tls.as_ref()
+    }
+
+    pub fn get_authorization(&self) -> Option<&AuthorizationConfig> {
+        self.authorization.as_ref()
+    }
+
+    pub fn get_operator(&self) -> Option<&OperatorConfig> {
+        self.operator.as_ref()
+    }
+
+    pub fn get_accounts(&self) -> Option<&HashMap<String, AccountConfig>> {
+        self.accounts.as_ref()
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct TlsConfig {
+    // PEM格式的证书链和私钥
+    cert_file: String,
+    key_file: String,
+    // 配置了CA的话, 客户端发送的证书要由这个CA签发
+    ca_file: Option<String>,
+    // 单位是秒, 可以是小数
+    timeout: Option<f64>,
+    // 连接之后马上开始握手, 而不是先发送INFO再握手
+    tls_first: Option<bool>,
+    // 客户端必须发送由CA签发的证书
+    verify: Option<bool>,
+    // 在verify的基础上, 用证书里面的email或者subject作为用户名
+    verify_and_map: Option<bool>,
+}
+
+impl TlsConfig {
+    pub fn get_cert_file(&self) -> &str {
+        &self.cert_file
+    }
+
+    pub fn get_key_file(&self) -> &str {
+        &self.key_file
+    }
+
+    pub fn get_ca_file(&self) -> Option<&str> {
+        self.ca_file.as_deref()
+    }
+
+    pub fn get_timeout(&self) -> f64 {
+        self.timeout.unwrap_or(DEFAULT_TLS_TIMEOUT)
+    }
+
+    pub fn is_tls_first(&self) -> bool {
+        self.tls_first.unwrap_or(false)
+    }
+
+    pub fn is_verify(&self) -> bool {
+        self.verify.unwrap_or(false) || self.is_verify_and_map()
+    }
+
+    pub fn is_verify_and_map(&self) -> bool {
+        self.verify_and_map.unwrap_or(false)
+    }
+}
+
+// token和users只能配置一种
+// token和password都可以是bcrypt加密之后的值
+#[derive(Deserialize, Debug, Clone)]
+pub struct AuthorizationConfig {
+    token: Option<String>,
+    users: Option<Vec<UserConfig>>,
+    // 用NKey签名认证的用户, 只需要配置公钥
+    nkeys: Option<Vec<NkeyConfig>>,
+    // 单位是秒, 可以是小数
+    timeout: Option<f64>,
+}
+
+impl AuthorizationConfig {
+    pub fn get_token(&self) -> Option<&str> {
+        self.token.as_deref()
+    }
+
+    pub fn get_users(&self) -> &[UserConfig] {
+        self.users.as_deref().unwrap_or_default()
+    }
+
+    pub fn get_nkeys(&self) -> &[NkeyConfig] {
+        self.nkeys.as_deref().unwrap_or_default()
+    }
+
+    pub fn get_timeout(&self) -> f64 {
+        self.timeout.unwrap_or(DEFAULT_AUTH_TIMEOUT)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct AccountConfig {
+    users: Option<Vec<UserConfig>>,
+    nkeys: Option<Vec<NkeyConfig>>,
+    // 共享给其他账户的subject
+    exports: Option<Vec<ExportConfig>>,
+    // 从其他账户导入的subject
+    imports: Option<Vec<ImportConfig>>,
+}
+
+impl AccountConfig {
+    pub fn get_users(&self) -> &[UserConfig] {
+        self.users.as_deref().unwrap_or_default()
+    }
+
+    pub fn get_nkeys(&self) -> &[NkeyConfig] {
+        self.nkeys.as_deref().unwrap_or_default()
+    }
+
+    pub fn get_exports(&self) -> &[ExportConfig] {
+        self.exports.as_deref().unwrap_or_default()
+    }
+
+    pub fn get_imports(&self) -> &[ImportConfig] {
+        self.imports.as_deref().unwrap_or_default()
+    }
+}
+
+// accounts没有配置的话所有账户都可以导入, 否则只有列出来的账户可以导入
+// 不带tag, 按照字段区分类型, 所以每种都不能有多余的字段, 否则stream和service的字段混在一起也能解析
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum ExportConfig {
+    // 发布到这个subject的消息, 导入的账户也能收到
+    Stream(StreamExportConfig),
+    // 导入的账户可以发送请求到这个subject, 回复会发送回导入的账户
+    Service(ServiceExportConfig),
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct StreamExportConfig {
+    stream: String,
+    accounts: Option<Vec<String>>,
+}
+
+impl StreamExportConfig {
+    pub fn get_stream(&self) -> &str {
+        &self.stream
+    }
+
+    pub fn get_accounts(&self) -> Option<&[String]> {
+        self.accounts.as_deref()
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ServiceExportConfig {
+    service: String,
+    accounts: Option<Vec<String>>,
+}
+
+impl ServiceExportConfig {
+    pub fn get_service(&self) -> &str {
+        &self.service
+    }
+
+    pub fn get_accounts(&self) -> Option<&[String]> {
+        self.accounts.as_deref()
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum ImportConfig {
+    // 收到的消息的subject前面加上prefix
+    Stream(StreamImportConfig),
+    // 在自己的账户里面发布到to, 就是请求导出账户的subject
+    Service(ServiceImportConfig),
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct StreamImportConfig {
+    stream: ImportSourceConfig,
+    prefix: Option<String>,
+}
+
+impl StreamImportConfig {
+    pub fn get_stream(&self) -> &ImportSourceConfig {
+        &self.stream
+    }
+
+    pub fn get_prefix(&self) -> Option<&str> {
+        self.prefix.as_deref()
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ServiceImportConfig {
+    service: ImportSourceConfig,
+    to: Option<String>,
+}
+
+impl ServiceImportConfig {
+    pub fn get_service(&self) -> &ImportSourceConfig {
+        &self.service
+    }
+
+    pub fn get_to(&self) -> Option<&str> {
+        self.to.as_deref()
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ImportSourceConfig {
+    account: String,
+    subject: String,
+}
+
+impl ImportSourceConfig {
+    pub fn get_account(&self) -> &str {
+        &self.account
+    }
+
+    pub fn get_subject(&self) -> &str {
+        &self.subject
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct UserConfig {
+    user: String,
+    // 用证书对应的用户不需要密码
+    password: Option<String>,
+    // 没有配置的话可以发布和订阅所有的subject
+    permissions: Option<PermissionsConfig>,
+}
+
+impl UserConfig {
+    pub fn get_user(&self) -> &str {
+        &self.user
+    }
+
+    pub fn get_password(&self) -> Option<&str> {
+        self.password.as_deref()
+    }
+
+    pub fn get_permissions(&self) -> Option<&PermissionsConfig> {
+        self.permissions.as_ref()
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct NkeyConfig {
+    nkey: String,
+    permissions: Option<PermissionsConfig>,
+}
+
+impl NkeyConfig {
+    pub fn get_nkey(&self) -> &str {
+        &self.nkey
+    }
+
+    pub fn get_permissions(&self) -> Option<&PermissionsConfig> {
+        self.permissions.as_ref()
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct PermissionsConfig {
+    #[serde(default)]
+    publish: PermissionConfig,
+    #[serde(default)]
+    subscribe: PermissionConfig,
+    // 可以向收到的消息的reply发布, 不受publish的限制
+    allow_responses: Option<ResponsePermissionConfig>,
+}
+
+impl PermissionsConfig {
+    pub fn get_publish(&self) -> &PermissionConfig {
+        &self.publish
+    }
+
+    pub fn get_subscribe(&self) -> &PermissionConfig {
+        &self.subscribe
+    }
+
+    pub fn get_allow_responses(&self) -> Option<&ResponsePermissionConfig> {
+        self.allow_responses.as_ref()
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct ResponsePermissionConfig {
+    // 每个reply最多可以发布的次数
+    max: Option<usize>,
+    // 单位是秒, 可以是小数, 没有配置的话2分钟过期
+    expires: Option<f64>,
+}
+
+impl ResponsePermissionConfig {
+    pub fn get_max(&self) -> Option<usize> {
+        self.max
+    }
+
+    pub fn get_expires(&self) -> Option<f64> {
+        self.expires
+    }
+}
+
+// subject可以带通配符, allow为空表示全部允许, deny优先
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct PermissionConfig {
+    #[serde(default)]
+    allow: Vec<String>,
+    #[serde(default)]
+    deny: Vec<String>,
+}
+
+impl PermissionConfig {
+    pub fn get_allow(&self) -> &[String] {
+        &self.allow
+    }
+
+    pub fn get_deny(&self) -> &[String] {
+        &self.deny
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct OperatorConfig {
+    // operator自己签名的JWT文件
+    jwt_file: String,
+    // 怎么根据账户的公钥找到账户的JWT
+    resolver: ResolverConfig,
+}
+
+impl OperatorConfig {
+    pub fn get_jwt_file(&self) -> &str {
+        &self.jwt_file
+    }
+
+    pub fn get_resolver(&self) -> &ResolverConfig {
+        &self.resolver
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ResolverConfig {
+    // 账户的JWT直接写在配置里面, key是账户的公钥
+    Memory {
+        #[serde(default)]
+        accounts: HashMap<String, String>,
+    },
+    // 目录下面的<账户公钥>.jwt文件, 每次认证的时候读取, 修改之后不需要重启
+    Dir { dir: String },
 }
 
 #[derive(Deserialize, Debug, Clone)]
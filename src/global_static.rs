@@ -1,4 +1,5 @@
 use super::config::Config;
+use super::server::Stats;
 use lazy_static::lazy_static;
 
 lazy_static! {
@@ -8,4 +9,5 @@ lazy_static! {
             panic!("{:?}", e);
         }
     };
+    pub static ref STATS: Stats = Stats::new();
 }
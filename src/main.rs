@@ -1,8 +1,5 @@
-use beaver;
 use beaver::server::Server;
-use env_logger;
 use log::error;
-use tokio;
 
 #[tokio::main]
 async fn main() {
@@ -0,0 +1,437 @@
+use super::service::ArcSubList;
+use super::sub_list::{is_subset_match, is_valid_literal_subject, is_valid_subject, SubList};
+use crate::config::{AccountConfig, ExportConfig, ImportConfig};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock, Weak};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use uuid::Uuid;
+
+// 没有配置账户的用户, 以及不需要认证的连接都属于这个账户, 和nats的名字一样
+pub(super) const GLOBAL_ACCOUNT: &str = "$G";
+
+// 导入的service的请求, reply换成这个前缀开头的subject, 导出账户回复之后再换回原来的reply
+const RESPONSE_PREFIX: &str = "_R_.";
+// 一直没有回复的请求最多保存2分钟, 和nats一样
+const RESPONSE_TTL: Duration = Duration::from_secs(120);
+const RESPONSES_MAX: usize = 4096;
+const RESPONSES_SWEEP: usize = 1024;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("account `{0}` not found")]
+    AccountNotFound(String),
+
+    #[error("invalid subject `{0}`")]
+    InvalidSubject(String),
+
+    #[error("account `{account}` does not export `{subject}` to `{importer}`")]
+    ExportNotFound {
+        account: String,
+        subject: String,
+        importer: String,
+    },
+}
+
+// 导入了这个账户的stream的账户
+#[derive(Debug)]
+struct StreamImport {
+    account: Weak<Account>,
+    subject: String,
+    prefix: Option<String>,
+}
+
+// 这个账户导入的service, 发布到to的消息发送到导出账户的subject
+// subject为None的时候不改变subject
+#[derive(Debug)]
+struct ServiceImport {
+    account: Weak<Account>,
+    to: String,
+    subject: Option<String>,
+}
+
+// 导出的service还没有回复的请求, 回复发送回导入账户原来的reply
+#[derive(Debug)]
+struct ServiceResponse {
+    account: Weak<Account>,
+    reply_to: String,
+    deadline: Instant,
+}
+
+// 消息除了发送给自己账户的订阅以外, 还要发送到其他账户的订阅列表
+#[derive(Debug)]
+pub(super) struct Route {
+    sub_list: ArcSubList,
+    subject: String,
+    reply_to: Option<String>,
+}
+
+impl Route {
+    pub(super) fn get_sub_list(&self) -> &ArcSubList {
+        &self.sub_list
+    }
+
+    pub(super) fn get_subject(&self) -> &str {
+        &self.subject
+    }
+
+    pub(super) fn get_reply_to(&self) -> Option<&str> {
+        self.reply_to.as_deref()
+    }
+}
+
+// 一个账户, 有自己独立的订阅列表
+// 导入导出的关系在启动的时候建立, 账户之间互相引用, 所以保存的是Weak
+#[derive(Debug)]
+pub(super) struct Account {
+    name: String,
+    sub_list: ArcSubList,
+    stream_imports: RwLock<Vec<StreamImport>>,
+    service_imports: RwLock<Vec<ServiceImport>>,
+    responses: Mutex<HashMap<String, ServiceResponse>>,
+}
+
+impl Account {
+    fn new(name: String) -> Self {
+        Self {
+            name,
+            sub_list: Arc::new(RwLock::new(SubList::new())),
+            stream_imports: RwLock::new(Vec::new()),
+            service_imports: RwLock::new(Vec::new()),
+            responses: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub(super) fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    pub(super) fn get_sub_list(&self) -> &ArcSubList {
+        &self.sub_list
+    }
+
+    // 发布到这个账户的消息还要发送到哪些账户
+    // 导出的stream发送给所有导入的账户, 导入的service发送给导出的账户, 回复发送回导入的账户
+    // 发送到其他账户的消息不会再继续转发, 所以不会出现循环
+    pub(super) fn get_routes(self: &Arc<Self>, subject: &str, reply_to: Option<&str>) -> Vec<Route> {
+        let mut routes: Vec<Route> = Vec::new();
+
+        for import in read(&self.stream_imports).iter() {
+            if !is_subset_match(&import.subject, subject) {
+                continue;
+            }
+            if let Some(account) = import.account.upgrade() {
+                let subject: String = match &import.prefix {
+                    Some(prefix) => format!("{}.{}", prefix, subject),
+                    None => subject.to_string(),
+                };
+                routes.push(Route {
+                    sub_list: account.sub_list.clone(),
+                    subject,
+                    reply_to: reply_to.map(|reply_to| reply_to.to_string()),
+                });
+            }
+        }
+
+        let service = read(&self.service_imports).iter().find_map(|import| {
+            if !is_subset_match(&import.to, subject) {
+                return None;
+            }
+            let account: Arc<Account> = import.account.upgrade()?;
+            let subject: String = import.subject.as_deref().unwrap_or(subject).to_string();
+            Some((account, subject))
+        });
+        if let Some((account, subject)) = service {
+            let reply_to: Option<String> =
+                reply_to.map(|reply_to| account.add_response(Arc::downgrade(self), reply_to));
+            routes.push(Route {
+                sub_list: account.sub_list.clone(),
+                subject,
+                reply_to,
+            });
+        }
+
+        if subject.starts_with(RESPONSE_PREFIX) {
+            if let Some(response) = self.lock_responses().remove(subject) {
+                if let (true, Some(account)) = (
+                    response.deadline > Instant::now(),
+                    response.account.upgrade(),
+                ) {
+                    routes.push(Route {
+                        sub_list: account.sub_list.clone(),
+                        subject: response.reply_to,
+                        reply_to: None,
+                    });
+                }
+            }
+        }
+        routes
+    }
+
+    // 给导入账户的请求生成一个新的reply, 只能回复一次
+    fn add_response(&self, account: Weak<Account>, reply_to: &str) -> String {
+        let now: Instant = Instant::now();
+        let subject: String = format!("{}{}", RESPONSE_PREFIX, Uuid::new_v4().to_simple());
+        let mut responses = self.lock_responses();
+        if responses.len() >= RESPONSES_MAX {
+            responses.retain(|_, response| response.deadline > now);
+            if responses.len() >= RESPONSES_MAX {
+                let removed: Vec<String> = responses.keys().take(RESPONSES_SWEEP).cloned().collect();
+                for key in removed {
+                    responses.remove(&key);
+                }
+            }
+        }
+        responses.insert(
+            subject.clone(),
+            ServiceResponse {
+                account,
+                reply_to: reply_to.to_string(),
+                deadline: now + RESPONSE_TTL,
+            },
+        );
+        subject
+    }
+
+    fn lock_responses(&self) -> MutexGuard<'_, HashMap<String, ServiceResponse>> {
+        self.responses.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+fn read<T>(lock: &RwLock<T>) -> std::sync::RwLockReadGuard<'_, T> {
+    lock.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn write<T>(lock: &RwLock<T>) -> std::sync::RwLockWriteGuard<'_, T> {
+    lock.write().unwrap_or_else(PoisonError::into_inner)
+}
+
+// 导出的subject包含导入的subject, 并且允许这个账户导入
+fn find_export(config: &AccountConfig, importer: &str, service: bool, subject: &str) -> bool {
+    let is_allowed = |accounts: Option<&[String]>| {
+        accounts.is_none_or(|accounts| accounts.iter().any(|account| account == importer))
+    };
+    config.get_exports().iter().any(|export| match export {
+        ExportConfig::Stream(export) if !service => {
+            is_subset_match(export.get_stream(), subject) && is_allowed(export.get_accounts())
+        }
+        ExportConfig::Service(export) if service => {
+            is_subset_match(export.get_service(), subject) && is_allowed(export.get_accounts())
+        }
+        _ => false,
+    })
+}
+
+// 所有的账户, 配置的账户启动的时候创建, JWT的账户在第一个用户连接的时候创建
+#[derive(Debug)]
+pub(super) struct Accounts {
+    global: Arc<Account>,
+    accounts: RwLock<HashMap<String, Arc<Account>>>,
+}
+
+impl Accounts {
+    pub(super) fn new(config: Option<&HashMap<String, AccountConfig>>) -> Result<Self, Error> {
+        let mut accounts: HashMap<String, Arc<Account>> = config
+            .into_iter()
+            .flat_map(|config| config.keys())
+            .map(|name| (name.clone(), Arc::new(Account::new(name.clone()))))
+            .collect();
+        // 配置了$G的话, 全局账户也可以导入导出
+        let global: Arc<Account> = accounts
+            .remove(GLOBAL_ACCOUNT)
+            .unwrap_or_else(|| Arc::new(Account::new(GLOBAL_ACCOUNT.to_string())));
+        let get = |name: &str| -> Result<&Arc<Account>, Error> {
+            if name == GLOBAL_ACCOUNT {
+                return Ok(&global);
+            }
+            accounts
+                .get(name)
+                .ok_or_else(|| Error::AccountNotFound(name.to_string()))
+        };
+
+        // 导入的时候检查导出账户的配置, 没有导出或者不允许导入的话启动的时候就报错
+        for (name, account_config) in config.into_iter().flatten() {
+            let importer: &Arc<Account> = get(name)?;
+            for import in account_config.get_imports() {
+                let (source, service) = match import {
+                    ImportConfig::Stream(import) => (import.get_stream(), false),
+                    ImportConfig::Service(import) => (import.get_service(), true),
+                };
+                let subject: &str = source.get_subject();
+                if !is_valid_subject(subject) {
+                    return Err(Error::InvalidSubject(subject.to_string()));
+                }
+                let exporter: &Arc<Account> = get(source.get_account())?;
+                let exported: bool = config
+                    .and_then(|config| config.get(source.get_account()))
+                    .is_some_and(|config| find_export(config, name, service, subject));
+                if !exported {
+                    return Err(Error::ExportNotFound {
+                        account: source.get_account().to_string(),
+                        subject: subject.to_string(),
+                        importer: name.clone(),
+                    });
+                }
+
+                match import {
+                    ImportConfig::Stream(import) => {
+                        let prefix: Option<&str> = import.get_prefix();
+                        if let Some(prefix) = prefix.filter(|prefix| !is_valid_literal_subject(prefix)) {
+                            return Err(Error::InvalidSubject(prefix.to_string()));
+                        }
+                        write(&exporter.stream_imports).push(StreamImport {
+                            account: Arc::downgrade(importer),
+                            subject: subject.to_string(),
+                            prefix: prefix.map(str::to_string),
+                        });
+                    }
+                    // 换了subject的话只能是一对一, 不能带通配符
+                    ImportConfig::Service(import) => {
+                        let import: ServiceImport = match import.get_to() {
+                            Some(to) => {
+                                if !is_valid_literal_subject(to) {
+                                    return Err(Error::InvalidSubject(to.to_string()));
+                                }
+                                if !is_valid_literal_subject(subject) {
+                                    return Err(Error::InvalidSubject(subject.to_string()));
+                                }
+                                ServiceImport {
+                                    account: Arc::downgrade(exporter),
+                                    to: to.to_string(),
+                                    subject: Some(subject.to_string()),
+                                }
+                            }
+                            None => ServiceImport {
+                                account: Arc::downgrade(exporter),
+                                to: subject.to_string(),
+                                subject: None,
+                            },
+                        };
+                        write(&importer.service_imports).push(import);
+                    }
+                }
+            }
+        }
+
+        Ok(Self {
+            global,
+            accounts: RwLock::new(accounts),
+        })
+    }
+
+    pub(super) fn get_global(&self) -> &Arc<Account> {
+        &self.global
+    }
+
+    pub(super) fn get_or_create(&self, name: &str) -> Arc<Account> {
+        if name == GLOBAL_ACCOUNT {
+            return self.global.clone();
+        }
+        if let Some(account) = read(&self.accounts).get(name) {
+            return account.clone();
+        }
+        write(&self.accounts)
+            .entry(name.to_string())
+            .or_insert_with(|| Arc::new(Account::new(name.to_string())))
+            .clone()
+    }
+}
+
+#[cfg(test)]
+fn test_accounts(config: &str) -> Result<Accounts, Error> {
+    let config: HashMap<String, AccountConfig> = toml::from_str(config).unwrap();
+    Accounts::new(Some(&config))
+}
+
+#[test]
+fn accounts_get_or_create() {
+    let accounts: Accounts =
+        test_accounts("orders = { users = [{ user = \"alice\" }] }\npayments = {}").unwrap();
+
+    let orders: Arc<Account> = accounts.get_or_create("orders");
+    assert_eq!(orders.get_name(), "orders");
+    assert!(Arc::ptr_eq(&orders, &accounts.get_or_create("orders")));
+    assert!(!Arc::ptr_eq(
+        orders.get_sub_list(),
+        accounts.get_or_create("payments").get_sub_list()
+    ));
+    assert!(Arc::ptr_eq(&accounts.get_or_create(GLOBAL_ACCOUNT), accounts.get_global()));
+
+    // JWT的账户第一次用到的时候创建
+    let account: Arc<Account> = accounts.get_or_create("ADVV3FHXZ5QJHAXMQDXPJ3JJCKBX2LVGLVAO5T4ZQSV5B4VJA7UQD2AJ");
+    assert!(Arc::ptr_eq(
+        &account,
+        &accounts.get_or_create("ADVV3FHXZ5QJHAXMQDXPJ3JJCKBX2LVGLVAO5T4ZQSV5B4VJA7UQD2AJ")
+    ));
+}
+
+#[test]
+fn accounts_routes() {
+    let accounts: Accounts = test_accounts(
+        r#"
+        [orders]
+        exports = [{ stream = "orders.>" }, { service = "orders.lookup", accounts = ["payments"] }]
+        [payments]
+        imports = [
+            { stream = { account = "orders", subject = "orders.created" }, prefix = "ext" },
+            { service = { account = "orders", subject = "orders.lookup" }, to = "lookup" },
+        ]
+        "#,
+    )
+    .unwrap();
+    let orders: Arc<Account> = accounts.get_or_create("orders");
+    let payments: Arc<Account> = accounts.get_or_create("payments");
+
+    // 导出的stream加上前缀发送给导入的账户
+    let routes: Vec<Route> = orders.get_routes("orders.created", Some("_INBOX.a"));
+    assert_eq!(routes.len(), 1);
+    assert!(Arc::ptr_eq(routes[0].get_sub_list(), payments.get_sub_list()));
+    assert_eq!(routes[0].get_subject(), "ext.orders.created");
+    assert_eq!(routes[0].get_reply_to(), Some("_INBOX.a"));
+    assert!(orders.get_routes("orders.updated", None).is_empty());
+    assert!(payments.get_routes("orders.created", None).is_empty());
+
+    // 导入的service换成导出账户的subject, reply换成一次性的subject
+    let routes: Vec<Route> = payments.get_routes("lookup", Some("_INBOX.b"));
+    assert_eq!(routes.len(), 1);
+    assert!(Arc::ptr_eq(routes[0].get_sub_list(), orders.get_sub_list()));
+    assert_eq!(routes[0].get_subject(), "orders.lookup");
+    let reply_to: String = routes[0].get_reply_to().unwrap().to_string();
+    assert!(reply_to.starts_with(RESPONSE_PREFIX));
+
+    let routes: Vec<Route> = orders.get_routes(&reply_to, None);
+    assert_eq!(routes.len(), 1);
+    assert!(Arc::ptr_eq(routes[0].get_sub_list(), payments.get_sub_list()));
+    assert_eq!(routes[0].get_subject(), "_INBOX.b");
+    assert_eq!(routes[0].get_reply_to(), None);
+    assert!(orders.get_routes(&reply_to, None).is_empty());
+}
+
+#[test]
+fn accounts_invalid_imports() {
+    for config in &[
+        // 没有导出
+        "orders = {}\npayments = { imports = [{ stream = { account = \"orders\", subject = \"orders.>\" } }] }",
+        // 导入的范围比导出的大
+        "orders = { exports = [{ stream = \"orders.*\" }] }\npayments = { imports = [{ stream = { account = \"orders\", subject = \"orders.>\" } }] }",
+        // 类型不对
+        "orders = { exports = [{ stream = \"orders.>\" }] }\npayments = { imports = [{ service = { account = \"orders\", subject = \"orders.lookup\" } }] }",
+        // 不允许导入
+        "orders = { exports = [{ stream = \"orders.>\", accounts = [\"other\"] }] }\npayments = { imports = [{ stream = { account = \"orders\", subject = \"orders.>\" } }] }",
+        // 账户不存在
+        "payments = { imports = [{ stream = { account = \"orders\", subject = \"orders.>\" } }] }",
+        "orders = { exports = [{ service = \"orders.*\" }] }\npayments = { imports = [{ service = { account = \"orders\", subject = \"orders.*\" }, to = \"lookup\" }] }",
+        "orders = { exports = [{ stream = \"orders.>\" }] }\npayments = { imports = [{ stream = { account = \"orders\", subject = \"orders.>\" }, prefix = \"ext.*\" }] }",
+    ] {
+        assert!(test_accounts(config).is_err(), "{}", config);
+    }
+
+    // stream和service的字段不能混在一起
+    for config in &[
+        "orders = { exports = [{ stream = \"orders.>\", service = \"orders.lookup\" }] }",
+        "orders = { exports = [{ stream = \"orders.>\" }] }\npayments = { imports = [{ stream = { account = \"orders\", subject = \"orders.>\" }, to = \"x\" }] }",
+        "orders = { exports = [{ service = \"orders.lookup\" }] }\npayments = { imports = [{ service = { account = \"orders\", subject = \"orders.lookup\" }, prefix = \"x\" }] }",
+    ] {
+        assert!(toml::from_str::<HashMap<String, AccountConfig>>(config).is_err(), "{}", config);
+    }
+}
@@ -0,0 +1,543 @@
+use super::account::GLOBAL_ACCOUNT;
+use super::decode::{Connect, Error as ProtocolError};
+use super::jwt::{Claims, Operator, UserClaims, UserLimits};
+use super::permissions::Permissions;
+use crate::config::{AccountConfig, AuthorizationConfig, NkeyConfig, PermissionsConfig, UserConfig};
+use data_encoding::{BASE64, BASE64URL_NOPAD};
+use log::debug;
+use nkeys::KeyPair;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use thiserror::Error;
+use tokio::task::spawn_blocking;
+
+// 没有配置authorization的时候, 虽然不需要认证, 也要有一个超时时间
+const DEFAULT_AUTH_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("token and users can not be used together")]
+    TokenWithUsers,
+    #[error("invalid user nkey {0}")]
+    InvalidNkey(String),
+
+    #[error("invalid permission subject {0}")]
+    InvalidPermission(String),
+
+    #[error("duplicate user {0}")]
+    DuplicateUser(String),
+
+    #[error("invalid authorization timeout {0}")]
+    InvalidTimeout(f64),
+
+    #[error("invalid allow_responses expires {0}")]
+    InvalidResponseExpires(f64),
+}
+
+// 认证通过之后连接对应的用户
+#[derive(Debug, Default)]
+pub(super) struct User {
+    name: String,
+    password: Option<String>,
+    // 没有的话表示可以发布和订阅所有的subject
+    permissions: Option<Permissions>,
+    max_subs: Option<usize>,
+    max_payload: Option<usize>,
+    // JWT的过期时间, 过期之后断开连接
+    expires: Option<SystemTime>,
+    // 用户所属的账户, JWT的用户是账户的公钥
+    account: String,
+}
+
+impl User {
+    fn new(
+        name: String,
+        password: Option<String>,
+        permissions: Option<&PermissionsConfig>,
+        account: &str,
+    ) -> Result<Self, Error> {
+        let expires: Option<f64> = permissions
+            .and_then(|permissions| permissions.get_allow_responses())
+            .and_then(|responses| responses.get_expires());
+        if let Some(expires) = expires.filter(|expires| Duration::try_from_secs_f64(*expires).is_err()) {
+            return Err(Error::InvalidResponseExpires(expires));
+        }
+        let permissions: Option<Permissions> = permissions.map(Permissions::from_config);
+        // 权限里面的subject不合法的话启动的时候就报错
+        if let Some(subject) = permissions.as_ref().and_then(Permissions::find_invalid) {
+            return Err(Error::InvalidPermission(subject.to_string()));
+        }
+        Ok(Self {
+            name,
+            password,
+            permissions,
+            account: account.to_string(),
+            ..Default::default()
+        })
+    }
+
+    // JWT认证的用户, 用户名是用户的公钥
+    fn from_claims(claims: &Claims<UserClaims>, account: String) -> Self {
+        let limits: &UserLimits = claims.get_claims().get_limits();
+        Self {
+            name: claims.get_subject().to_string(),
+            password: None,
+            permissions: Some(limits.get_permissions()),
+            max_subs: limits.get_max_subs(),
+            max_payload: limits.get_max_payload(),
+            expires: claims.get_expires(),
+            account,
+        }
+    }
+
+    pub(super) fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    pub(super) fn get_permissions(&self) -> Option<&Permissions> {
+        self.permissions.as_ref()
+    }
+
+    pub(super) fn get_max_subs(&self) -> Option<usize> {
+        self.max_subs
+    }
+
+    pub(super) fn get_max_payload(&self) -> Option<usize> {
+        self.max_payload
+    }
+
+    pub(super) fn get_expires(&self) -> Option<SystemTime> {
+        self.expires
+    }
+
+    pub(super) fn get_account(&self) -> &str {
+        &self.account
+    }
+}
+
+// 服务端配置的认证方式, 启动的时候生成一次, 所有连接共用
+#[derive(Debug)]
+pub(super) struct Auth {
+    token: Option<String>,
+    users: HashMap<String, Arc<User>>,
+    // 公钥对应的用户, 用户名就是公钥
+    nkeys: HashMap<String, Arc<User>>,
+    // 配置了operator的话, 可以用JWT认证
+    operator: Option<Operator>,
+    timeout: Duration,
+}
+
+impl Auth {
+    pub(super) fn new(config: Option<&AuthorizationConfig>) -> Result<Self, Error> {
+        let mut auth: Self = Self {
+            token: None,
+            users: HashMap::new(),
+            nkeys: HashMap::new(),
+            operator: None,
+            timeout: DEFAULT_AUTH_TIMEOUT,
+        };
+        let config: &AuthorizationConfig = match config {
+            Some(config) => config,
+            None => return Ok(auth),
+        };
+        if config.get_token().is_some()
+            && !(config.get_users().is_empty() && config.get_nkeys().is_empty())
+        {
+            return Err(Error::TokenWithUsers);
+        }
+
+        auth.add_users(GLOBAL_ACCOUNT, config.get_users(), config.get_nkeys())?;
+        auth.token = config.get_token().map(|token| token.to_string());
+        // 负数, NaN或者太大的超时时间启动的时候就报错
+        auth.timeout = Duration::try_from_secs_f64(config.get_timeout())
+            .map_err(|_| Error::InvalidTimeout(config.get_timeout()))?;
+        Ok(auth)
+    }
+
+    // 账户里面的用户, 认证之后连接属于这个账户
+    pub(super) fn add_account(mut self, name: &str, config: &AccountConfig) -> Result<Self, Error> {
+        if self.token.is_some() && !(config.get_users().is_empty() && config.get_nkeys().is_empty()) {
+            return Err(Error::TokenWithUsers);
+        }
+        self.add_users(name, config.get_users(), config.get_nkeys())?;
+        Ok(self)
+    }
+
+    // 所有账户的用户都在一起查找, 所以用户名不能重复
+    fn add_users(
+        &mut self,
+        account: &str,
+        users: &[UserConfig],
+        nkeys: &[NkeyConfig],
+    ) -> Result<(), Error> {
+        for config in users {
+            let name: &str = config.get_user();
+            if self.users.contains_key(name) {
+                return Err(Error::DuplicateUser(name.to_string()));
+            }
+            let password: Option<String> = config.get_password().map(|password| password.to_string());
+            let user: User = User::new(name.to_string(), password, config.get_permissions(), account)?;
+            self.users.insert(name.to_string(), Arc::new(user));
+        }
+        for config in nkeys {
+            let nkey: &str = config.get_nkey();
+            // 只能用用户的公钥, 启动的时候就检查出来
+            if !nkey.starts_with('U') || KeyPair::from_public_key(nkey).is_err() {
+                return Err(Error::InvalidNkey(nkey.to_string()));
+            }
+            if self.nkeys.contains_key(nkey) {
+                return Err(Error::DuplicateUser(nkey.to_string()));
+            }
+            let user: User = User::new(nkey.to_string(), None, config.get_permissions(), account)?;
+            self.nkeys.insert(nkey.to_string(), Arc::new(user));
+        }
+        Ok(())
+    }
+
+    pub(super) fn set_operator(mut self, operator: Operator) -> Self {
+        self.operator = Some(operator);
+        self
+    }
+
+    // 配置了token, 用户或者operator的话, 客户端必须先认证
+    pub(super) fn is_required(&self) -> bool {
+        self.token.is_some()
+            || !self.users.is_empty()
+            || !self.nkeys.is_empty()
+            || self.operator.is_some()
+    }
+
+    pub(super) fn get_timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    // 检查CONNECT里面的token, NKey签名或者用户名密码
+    // token认证没有对应的用户, 所以通过的时候返回None
+    pub(super) async fn authenticate(
+        &self,
+        connect: &Connect,
+        nonce: &str,
+    ) -> Result<Option<Arc<User>>, ProtocolError> {
+        if let (Some(operator), Some(jwt)) = (&self.operator, connect.get_jwt()) {
+            return Self::verify_jwt(operator, jwt, connect.get_sig(), nonce).await;
+        }
+        if let Some(nkey) = connect.get_nkey() {
+            return self.verify_nkey(nkey, connect.get_sig(), nonce);
+        }
+        if let Some(token) = &self.token {
+            return match connect.get_auth_token() {
+                Some(auth_token) if verify_password(token, auth_token).await => Ok(None),
+                _ => Err(ProtocolError::AuthorizationViolation),
+            };
+        }
+
+        let user: &Arc<User> = match connect.get_user().and_then(|name| self.users.get(name)) {
+            Some(user) => user,
+            None => return Err(ProtocolError::AuthorizationViolation),
+        };
+        match (&user.password, connect.get_pass()) {
+            (Some(password), Some(pass)) if verify_password(password, pass).await => {
+                Ok(Some(user.clone()))
+            }
+            _ => Err(ProtocolError::AuthorizationViolation),
+        }
+    }
+
+    // 客户端用私钥对INFO里面的nonce签名, 用配置的公钥验证签名
+    fn verify_nkey(
+        &self,
+        nkey: &str,
+        sig: Option<&str>,
+        nonce: &str,
+    ) -> Result<Option<Arc<User>>, ProtocolError> {
+        let user: &Arc<User> = self
+            .nkeys
+            .get(nkey)
+            .ok_or(ProtocolError::AuthorizationViolation)?;
+        if !verify_signature(nkey, sig, nonce) {
+            return Err(ProtocolError::AuthorizationViolation);
+        }
+        Ok(Some(user.clone()))
+    }
+
+    // 用户的JWT要能追溯到operator, 不是bearer的话还要用用户的私钥对nonce签名
+    async fn verify_jwt(
+        operator: &Operator,
+        jwt: &str,
+        sig: Option<&str>,
+        nonce: &str,
+    ) -> Result<Option<Arc<User>>, ProtocolError> {
+        let (claims, account) = operator.verify_user(jwt).await.map_err(|e| {
+            debug!("jwt error {:?}", e);
+            ProtocolError::AuthorizationViolation
+        })?;
+        let limits: &UserLimits = claims.get_claims().get_limits();
+        if !limits.is_bearer_token() && !verify_signature(claims.get_subject(), sig, nonce) {
+            return Err(ProtocolError::AuthorizationViolation);
+        }
+        debug!(
+            "user {} name {} account {}",
+            claims.get_subject(),
+            claims.get_name(),
+            account
+        );
+        Ok(Some(Arc::new(User::from_claims(&claims, account))))
+    }
+
+    // 按照客户端证书里面的身份顺序查找, 第一个配置了的用户就是这个连接的用户
+    pub(super) fn map_identities(&self, identities: &[String]) -> Option<Arc<User>> {
+        identities
+            .iter()
+            .find_map(|identity| self.users.get(identity).cloned())
+    }
+}
+
+// 客户端用私钥对nonce的签名
+// 签名是不带填充的base64url, 兼容一下标准的base64
+fn verify_signature(public_key: &str, sig: Option<&str>, nonce: &str) -> bool {
+    let sig: Vec<u8> = match sig.and_then(|sig| {
+        BASE64URL_NOPAD
+            .decode(sig.as_bytes())
+            .or_else(|_| BASE64.decode(sig.as_bytes()))
+            .ok()
+    }) {
+        Some(sig) => sig,
+        None => return false,
+    };
+    KeyPair::from_public_key(public_key)
+        .and_then(|key_pair| key_pair.verify(nonce.as_bytes(), &sig))
+        .is_ok()
+}
+
+// 和nats一样, 以$2开头的是bcrypt加密之后的值, 其他的直接比较
+async fn verify_password(expected: &str, password: &str) -> bool {
+    if expected.starts_with("$2") {
+        let expected: String = expected.to_string();
+        let password: String = password.to_string();
+        // bcrypt的计算比较耗时, 不能阻塞其他连接
+        spawn_blocking(move || bcrypt::verify(password, &expected).unwrap_or(false))
+            .await
+            .unwrap_or(false)
+    } else {
+        constant_time_eq(expected.as_bytes(), password.as_bytes())
+    }
+}
+
+// 比较的时间和内容无关, 不能通过响应时间来猜密码
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+fn test_connect(connect: &str) -> Connect {
+    serde_json::from_str(connect).unwrap()
+}
+
+#[test]
+fn auth_map_identities() {
+    let config: AuthorizationConfig =
+        toml::from_str("users = [{ user = \"client@beaver.io\" }, { user = \"CN=client,O=beaver\" }]")
+            .unwrap();
+    let auth: Auth = Auth::new(Some(&config)).unwrap();
+
+    // email优先
+    let identities: Vec<String> = vec!["client@beaver.io".to_string(), "CN=client,O=beaver".to_string()];
+    let user = auth.map_identities(&identities).unwrap();
+    assert_eq!(user.get_name(), "client@beaver.io");
+
+    let identities: Vec<String> = vec!["other@beaver.io".to_string(), "CN=client,O=beaver".to_string()];
+    let user = auth.map_identities(&identities).unwrap();
+    assert_eq!(user.get_name(), "CN=client,O=beaver");
+
+    assert!(auth.map_identities(&["CN=other".to_string()]).is_none());
+    assert!(Auth::new(None).unwrap().map_identities(&identities).is_none());
+}
+
+#[tokio::test]
+async fn auth_token() {
+    let config: AuthorizationConfig = toml::from_str("token = \"s3cr3t\"\ntimeout = 0.5").unwrap();
+    let auth: Auth = Auth::new(Some(&config)).unwrap();
+    assert!(auth.is_required());
+    assert_eq!(auth.get_timeout(), Duration::from_millis(500));
+
+    let connect: Connect = test_connect("{\"auth_token\":\"s3cr3t\"}");
+    assert!(auth.authenticate(&connect, "").await.unwrap().is_none());
+    assert!(auth.authenticate(&test_connect("{\"auth_token\":\"s3cr3\"}"), "").await.is_err());
+    assert!(auth.authenticate(&test_connect("{}"), "").await.is_err());
+
+    // bcrypt加密之后的token
+    let hash: String = bcrypt::hash("s3cr3t", 4).unwrap();
+    let config: AuthorizationConfig = toml::from_str(&format!("token = {:?}", hash)).unwrap();
+    let auth: Auth = Auth::new(Some(&config)).unwrap();
+    assert!(auth.authenticate(&connect, "").await.is_ok());
+    assert!(auth.authenticate(&test_connect("{\"auth_token\":\"other\"}"), "").await.is_err());
+
+    // token和users只能配置一种
+    let config: AuthorizationConfig =
+        toml::from_str("token = \"s3cr3t\"\nusers = [{ user = \"alice\", password = \"foo\" }]").unwrap();
+    assert!(Auth::new(Some(&config)).is_err());
+    assert!(!Auth::new(None).unwrap().is_required());
+
+    // 超时时间不合法
+    for timeout in &["-1.0", "nan", "inf"] {
+        let config: AuthorizationConfig =
+            toml::from_str(&format!("token = \"s3cr3t\"\ntimeout = {}", timeout)).unwrap();
+        assert!(matches!(Auth::new(Some(&config)), Err(Error::InvalidTimeout(_))));
+    }
+}
+
+#[tokio::test]
+async fn auth_users() {
+    let hash: String = bcrypt::hash("bar", 4).unwrap();
+    let config: AuthorizationConfig = toml::from_str(&format!(
+        "users = [{{ user = \"alice\", password = \"foo\" }}, {{ user = \"bob\", password = {:?} }}, {{ user = \"carol\" }}]",
+        hash
+    ))
+    .unwrap();
+    let auth: Auth = Auth::new(Some(&config)).unwrap();
+    assert!(auth.is_required());
+
+    let user = auth
+        .authenticate(&test_connect("{\"user\":\"alice\",\"pass\":\"foo\"}"), "")
+        .await
+        .unwrap();
+    assert_eq!(user.unwrap().get_name(), "alice");
+    let user = auth
+        .authenticate(&test_connect("{\"user\":\"bob\",\"pass\":\"bar\"}"), "")
+        .await
+        .unwrap();
+    assert_eq!(user.unwrap().get_name(), "bob");
+
+    for connect in &[
+        "{\"user\":\"alice\",\"pass\":\"bar\"}",
+        "{\"user\":\"bob\",\"pass\":\"foo\"}",
+        "{\"user\":\"alice\"}",
+        "{\"user\":\"dave\",\"pass\":\"foo\"}",
+        // 没有密码的用户只能通过证书认证
+        "{\"user\":\"carol\",\"pass\":\"\"}",
+        "{}",
+    ] {
+        assert!(auth.authenticate(&test_connect(connect), "").await.is_err());
+    }
+}
+
+#[tokio::test]
+async fn auth_nkeys() {
+    let key_pair: KeyPair = KeyPair::new_user();
+    let other: KeyPair = KeyPair::new_user();
+    let config: AuthorizationConfig =
+        toml::from_str(&format!("nkeys = [{{ nkey = {:?} }}]", key_pair.public_key())).unwrap();
+    let auth: Auth = Auth::new(Some(&config)).unwrap();
+    assert!(auth.is_required());
+
+    let nonce: &str = "a5f1b8e2c3d4";
+    let sign = |key_pair: &KeyPair, nonce: &str| {
+        BASE64URL_NOPAD.encode(&key_pair.sign(nonce.as_bytes()).unwrap())
+    };
+    let connect: Connect = test_connect(&format!(
+        "{{\"nkey\":{:?},\"sig\":{:?}}}",
+        key_pair.public_key(),
+        sign(&key_pair, nonce)
+    ));
+    let user = auth.authenticate(&connect, nonce).await.unwrap().unwrap();
+    assert_eq!(user.get_name(), key_pair.public_key());
+    // 每个连接的nonce都不一样, 别的连接的签名不能用
+    assert!(auth.authenticate(&connect, "other").await.is_err());
+
+    for connect in &[
+        // 没有签名
+        format!("{{\"nkey\":{:?}}}", key_pair.public_key()),
+        // 签名的私钥不对
+        format!("{{\"nkey\":{:?},\"sig\":{:?}}}", key_pair.public_key(), sign(&other, nonce)),
+        // 没有配置的公钥
+        format!("{{\"nkey\":{:?},\"sig\":{:?}}}", other.public_key(), sign(&other, nonce)),
+        format!("{{\"nkey\":{:?},\"sig\":\"!!\"}}", key_pair.public_key()),
+        "{}".to_string(),
+    ] {
+        assert!(auth.authenticate(&test_connect(connect), nonce).await.is_err());
+    }
+
+    // 只能配置用户的公钥
+    let config: AuthorizationConfig = toml::from_str(&format!(
+        "nkeys = [{{ nkey = {:?} }}]",
+        KeyPair::new_account().public_key()
+    ))
+    .unwrap();
+    assert!(Auth::new(Some(&config)).is_err());
+    let config: AuthorizationConfig = toml::from_str("nkeys = [{ nkey = \"UABC\" }]").unwrap();
+    assert!(Auth::new(Some(&config)).is_err());
+}
+
+#[tokio::test]
+async fn auth_permissions() {
+    let config: AuthorizationConfig = toml::from_str(
+        "users = [{ user = \"alice\", password = \"foo\", permissions = { publish = { allow = [\"orders.>\"] } } }, { user = \"bob\", password = \"bar\" }]",
+    )
+    .unwrap();
+    let auth: Auth = Auth::new(Some(&config)).unwrap();
+
+    let user = auth
+        .authenticate(&test_connect("{\"user\":\"alice\",\"pass\":\"foo\"}"), "")
+        .await
+        .unwrap()
+        .unwrap();
+    let permissions: &Permissions = user.get_permissions().unwrap();
+    assert!(permissions.can_publish("orders.created"));
+    assert!(!permissions.can_publish("payments.created"));
+    assert!(permissions.can_subscribe("payments.created"));
+
+    // 没有配置权限的用户什么都可以做
+    let user = auth
+        .authenticate(&test_connect("{\"user\":\"bob\",\"pass\":\"bar\"}"), "")
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(user.get_permissions().is_none());
+
+    let config: AuthorizationConfig = toml::from_str(
+        "users = [{ user = \"alice\", password = \"foo\", permissions = { subscribe = { deny = [\"foo.>.bar\"] } } }]",
+    )
+    .unwrap();
+    assert!(Auth::new(Some(&config)).is_err());
+
+    // allow_responses的过期时间不合法
+    for expires in &["-1.0", "nan", "inf"] {
+        let config: AuthorizationConfig = toml::from_str(&format!(
+            "users = [{{ user = \"alice\", password = \"foo\", permissions = {{ allow_responses = {{ expires = {} }} }} }}]",
+            expires
+        ))
+        .unwrap();
+        assert!(matches!(Auth::new(Some(&config)), Err(Error::InvalidResponseExpires(_))));
+    }
+}
+
+#[tokio::test]
+async fn auth_accounts() {
+    use std::collections::HashMap;
+
+    let config: AuthorizationConfig = toml::from_str("users = [{ user = \"alice\", password = \"foo\" }]").unwrap();
+    let accounts: HashMap<String, AccountConfig> = toml::from_str(
+        "orders = { users = [{ user = \"bob\", password = \"bar\" }] }\npayments = { users = [{ user = \"carol\", password = \"baz\" }] }",
+    )
+    .unwrap();
+    let mut auth: Auth = Auth::new(Some(&config)).unwrap();
+    for (name, account) in &accounts {
+        auth = auth.add_account(name, account).unwrap();
+    }
+
+    for (connect, account) in &[
+        ("{\"user\":\"alice\",\"pass\":\"foo\"}", GLOBAL_ACCOUNT),
+        ("{\"user\":\"bob\",\"pass\":\"bar\"}", "orders"),
+        ("{\"user\":\"carol\",\"pass\":\"baz\"}", "payments"),
+    ] {
+        let user = auth.authenticate(&test_connect(connect), "").await.unwrap().unwrap();
+        assert_eq!(user.get_account(), *account);
+    }
+
+    // 不同账户的用户名也不能重复
+    let account: AccountConfig = toml::from_str("users = [{ user = \"alice\", password = \"bar\" }]").unwrap();
+    assert!(Auth::new(Some(&config)).unwrap().add_account("orders", &account).is_err());
+    let config: AuthorizationConfig = toml::from_str("token = \"s3cr3t\"").unwrap();
+    assert!(Auth::new(Some(&config)).unwrap().add_account("orders", &account).is_err());
+}
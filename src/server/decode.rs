@@ -1,15 +1,21 @@
-use bytes::{Buf, BufMut, BytesMut};
-use serde;
+use super::sub_list::is_valid_subject;
+use crate::config::DEFAULT_MAX_CONTROL_LINE;
+use bytes::{Buf, Bytes, BytesMut};
 use serde_derive::Deserialize;
 use serde_json::{self, Error as SerdeError};
-use std::io::Error as IoError;
-use std::mem::swap;
 use std::str::from_utf8;
 use std::str::FromStr;
 use std::str::Utf8Error;
 use std::task::Poll;
 use thiserror::Error;
 
+// 没有设置限制的时候使用nats的默认值
+const DEFAULT_MAX_PAYLOAD: usize = 1024 * 1024;
+// 不超过这个长度的PUB消息从buff里面复制出来, 更长的消息直接和buff共享内存
+const COPY_FRAME_MAX: usize = 16 * 1024;
+
+// 协议错误, 会以 -ERR '<message>' 的形式回复给客户端
+// 具体的回复内容在encode里面
 #[derive(Debug, Error)]
 pub(super) enum Error {
     #[error("parse error")]
@@ -23,46 +29,170 @@ pub(super) enum Error {
 
     #[error("unknow protocol")]
     UnknownProtocol,
+
+    #[error("invalid subject")]
+    InvalidSubject,
+
+    #[error("invalid publish subject")]
+    InvalidPublishSubject,
+
+    #[error("stale connection")]
+    StaleConnection,
+
+    #[error("maximum payload violation")]
+    MaxPayload,
+
+    #[error("maximum control line exceeded")]
+    MaxControlLine,
+
+    #[error("authorization violation")]
+    AuthorizationViolation,
+
+    #[error("authentication timeout")]
+    AuthenticationTimeout,
+
+    #[error("user authentication expired")]
+    AuthenticationExpired,
+
+    #[error("permissions violation for publish to `{0}`")]
+    PublishViolation(String),
+
+    #[error("permissions violation for subscription to `{0}`")]
+    SubscriptionViolation(String),
+
+    #[error("maximum subscriptions exceeded")]
+    MaxSubscriptions,
+}
+
+impl Error {
+    // 致命的错误要断开连接,
+    // 其他的错误只需要丢掉出错的那一行, 然后继续解析
+    pub(super) fn is_fatal(&self) -> bool {
+        !matches!(
+            self,
+            Error::InvalidSubject
+                | Error::InvalidPublishSubject
+                | Error::PublishViolation(_)
+                | Error::SubscriptionViolation(_)
+                | Error::MaxSubscriptions
+        )
+    }
 }
 
 #[derive(Debug)]
 enum State {
-    ConnectPrepare,
     ConnectSpace,
     Conn,
     Start,
     SubSpace,
-    SubPrepare,
     PubSpace,
-    PubComplete,
+    HPub,
+    HPubSpace,
+    PubPayload,
     Ping,
-    PingPrepare,
     Pong,
-    PongPrepare,
     UnSu,
     UnSubPrepare,
 }
 
-// todo: 一个存储Connect信息的结构体
+// PUB和HPUB控制行解析出来的参数, 记录的是在buff中的下标
+// 这样在等待payload的时候就不需要重复解析控制行了
+#[derive(Debug, Default)]
+struct PubArg {
+    subject: (usize, usize),
+    reply_to: Option<(usize, usize)>,
+    payload_start: usize,
+    // HPUB的header长度, header是放在payload的前面, size里面包括了header的长度
+    header_size: Option<usize>,
+    size: usize,
+}
+
+// 存储Connect信息的结构体
 // 由于json结构是可以变化的, 每一次读取都有可能出现某些字段会有, 某些字段会没有
+// 所以全部字段都是Option, 没有发送的字段按照nats的默认值处理
+#[derive(Debug, Deserialize, PartialEq, Default)]
+pub(super) struct Connect {
+    verbose: Option<bool>,
+    pedantic: Option<bool>,
+    ssl_required: Option<bool>,
+    tls_required: Option<bool>,
+    name: Option<String>,
+    lang: Option<String>,
+    version: Option<String>,
+    protocol: Option<usize>,
+    echo: Option<bool>,
+    headers: Option<bool>,
+    user: Option<String>,
+    pass: Option<String>,
+    auth_token: Option<String>,
+    nkey: Option<String>,
+    sig: Option<String>,
+    jwt: Option<String>,
+}
+
+impl Connect {
+    pub(super) fn is_verbose(&self) -> bool {
+        self.verbose.unwrap_or(false)
+    }
+
+    pub(super) fn is_pedantic(&self) -> bool {
+        self.pedantic.unwrap_or(false)
+    }
+
+    pub(super) fn get_name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    pub(super) fn get_lang(&self) -> Option<&str> {
+        self.lang.as_deref()
+    }
+
+    pub(super) fn get_version(&self) -> Option<&str> {
+        self.version.as_deref()
+    }
+
+    // 默认是会收到自己发布的消息的
+    pub(super) fn is_echo(&self) -> bool {
+        self.echo.unwrap_or(true)
+    }
+
+    pub(super) fn supports_headers(&self) -> bool {
+        self.headers.unwrap_or(false)
+    }
+
+    pub(super) fn get_user(&self) -> Option<&str> {
+        self.user.as_deref()
+    }
+
+    pub(super) fn get_pass(&self) -> Option<&str> {
+        self.pass.as_deref()
+    }
+
+    pub(super) fn get_auth_token(&self) -> Option<&str> {
+        self.auth_token.as_deref()
+    }
+
+    pub(super) fn get_nkey(&self) -> Option<&str> {
+        self.nkey.as_deref()
+    }
 
-// #[derive(Debug, Deserialize, PartialEq, Default)]
-// pub(super) struct Connect {
-//     name: String,
-//     verbose: bool,
-//     ssl_required: bool,
-//     auth_required: bool,
-//     user: Option<String>,
-//     pass: Option<String>,
-//     lang: String,
-//     version: String,
-// }
+    pub(super) fn get_sig(&self) -> Option<&str> {
+        self.sig.as_deref()
+    }
+
+    pub(super) fn get_jwt(&self) -> Option<&str> {
+        self.jwt.as_deref()
+    }
+}
 
 #[derive(Debug, PartialEq)]
 pub(super) enum Message<'a> {
-    Connect(serde_json::Value),
+    Connect(Connect),
     Sub(&'a str, Option<&'a str>, &'a str),
-    Pub(&'a str, Option<&'a str>, &'a str),
+    // payload是直接从buff里面切出来的, 发送给订阅者的时候只需要增加引用计数
+    Pub(&'a str, Option<&'a str>, Bytes),
+    // subject, reply_to, headers, payload
+    HPub(&'a str, Option<&'a str>, Bytes, Bytes),
     UnSub(&'a str, Option<u32>),
     Pong,
     Ping,
@@ -92,7 +222,12 @@ pub(super) enum Message<'a> {
 pub(super) struct Decode {
     state: State,
     buff: BytesMut,
+    // 最后一个PUB消息从buff里面切出来的整段数据, subject和reply_to都是引用这里
+    frame: Bytes,
     end: usize,
+    pub_arg: PubArg,
+    max_payload: usize,
+    max_control_line: usize,
 }
 
 impl Decode {
@@ -100,12 +235,26 @@ impl Decode {
         Self {
             state: State::Start,
             buff: BytesMut::with_capacity(capacity),
+            frame: Bytes::new(),
             end: 0,
+            pub_arg: PubArg::default(),
+            max_payload: DEFAULT_MAX_PAYLOAD,
+            max_control_line: DEFAULT_MAX_CONTROL_LINE,
         }
     }
 
-    // 其实这里是没有考虑到接收的字节流是否会大于usize的大小, 
-    // 或者说没有考虑到整个服务是否能撑住
+    pub(super) fn set_max_payload(mut self, max_payload: usize) -> Self {
+        self.max_payload = max_payload;
+        self
+    }
+
+    pub(super) fn set_max_control_line(mut self, max_control_line: usize) -> Self {
+        self.max_control_line = max_control_line;
+        self
+    }
+
+    // 这里不限制buff的大小, 而是在decode的时候检查控制行和payload的长度,
+    // 超过限制就返回错误断开连接, 所以buff最多只会保存一个不完整的消息
     pub(super) fn set_buff(&mut self, buff: &[u8]) {
         self.buff.extend_from_slice(buff);
     }
@@ -113,32 +262,38 @@ impl Decode {
     // 在解析的过程中,
     // 有可能会出现windows客户端发\r\n的换行符,
     // 而不是 \n
-    pub(super) fn decode(&mut self) -> Result<Poll<Message>, Error> {
+    pub(super) fn decode(&mut self) -> Result<Poll<Message<'_>>, Error> {
         loop {
+            // payload是按照控制行给出的长度来读取的, 不能去找换行符,
+            // 因为payload里面本身就有可能带有\r\n或者是任意的二进制数据
+            if let State::PubPayload = self.state {
+                return self.pub_complete();
+            }
+
             if self.buff.has_remaining() {
                 if let Some(position) = self.buff[self.end..].iter().position(|item| *item == b'\n') {
                     self.end += position;
+                    if self.end > self.max_control_line {
+                        return Err(Error::MaxControlLine);
+                    }
                     match self.state {
                         State::Start => {
-                            if position >= 4 {
-                                match &self.buff[..4] {
-                                    b"CONN" => self.state = State::Conn,
-                                    b"SUB " => self.state = State::SubSpace,
-                                    b"PUB " => self.state = State::PubSpace,
-                                    b"PING" => self.state = State::Ping,
-                                    b"PONG" => self.state = State::Pong,
-                                    b"UNSU" => self.state = State::UnSu,
-                                    _ => return Err(Error::Parse),
-                                }
-                            } else {
-                                return Ok(Poll::Pending);
+                            // 已经收到了一整行, 长度不够的话肯定是不认识的协议
+                            if self.end < 4 {
+                                return Err(Error::UnknownProtocol);
+                            }
+                            match &self.buff[..4] {
+                                b"CONN" => self.state = State::Conn,
+                                b"SUB " => self.state = State::SubSpace,
+                                b"PUB " => self.state = State::PubSpace,
+                                b"HPUB" => self.state = State::HPub,
+                                b"PING" => self.state = State::Ping,
+                                b"PONG" => self.state = State::Pong,
+                                b"UNSU" => self.state = State::UnSu,
+                                _ => return Err(Error::UnknownProtocol),
                             }
                         }
                         State::Conn => {
-                            if self.buff.len() < 8 {
-                                self.end = self.buff.len();
-                                return Ok(Poll::Pending);
-                            }
                             if self.end >= 8 && &self.buff[4..8] == b"ECT " {
                                 self.state = State::ConnectSpace;
                             } else {
@@ -162,22 +317,29 @@ impl Decode {
                         State::Ping => return self.ping_message_complete(),
                         State::Pong => return self.pong_message_complete(),
                         State::PubSpace => {
-                            if let Some(position) = self
-                                .buff[(self.end + 1) ..]
-                                .iter()
-                                .position(|item| *item == b'\n')
-                            {
-                                self.end += position + 1;
-                                return self.pub_complete(4, self.end);
+                            if self.buff[self.end - 1] == b'\r' {
+                                self.pub_arg(4, self.end - 1)?;
                             } else {
-                                return Ok(Poll::Pending);
+                                self.pub_arg(4, self.end)?;
                             }
+                            self.state = State::PubPayload;
                         }
-                        State::UnSu => {
-                            if self.buff.len() < 6 {
-                                self.end = self.buff.len();
-                                return Ok(Poll::Pending);
+                        State::HPub => {
+                            if self.end >= 5 && self.buff[4] == b' ' {
+                                self.state = State::HPubSpace;
+                            } else {
+                                return Err(Error::Parse);
+                            }
+                        }
+                        State::HPubSpace => {
+                            if self.buff[self.end - 1] == b'\r' {
+                                self.hpub_arg(5, self.end - 1)?;
+                            } else {
+                                self.hpub_arg(5, self.end)?;
                             }
+                            self.state = State::PubPayload;
+                        }
+                        State::UnSu => {
                             if self.end >= 6 && &self.buff[4..6] == b"B " {
                                 self.state = State::UnSubPrepare;
                             } else {
@@ -191,9 +353,13 @@ impl Decode {
                                 return self.unsub_complete(6, self.end);
                             }
                         }
-                        _ => return Err(Error::Parse),
+                        State::PubPayload => unreachable!(),
                     }
                 } else {
+                    // 一直收不到换行符的话, 不能无限制的等待下去
+                    if self.buff.len() > self.max_control_line {
+                        return Err(Error::MaxControlLine);
+                    }
                     return Ok(Poll::Pending);
                 }
             } else {
@@ -202,42 +368,49 @@ impl Decode {
         }
     }
 
-    fn sub_message(&self, start: usize, end: usize) -> Result<Message, Error> {
+    fn sub_message(&self, start: usize, end: usize) -> Result<Message<'_>, Error> {
         let sub: Vec<&str> = {
             from_utf8(&self.buff[start..end])?
                 .split_whitespace()
                 .collect()
         };
-        match sub[..] {
-            [subject, sid] => Ok(Message::Sub(subject, None, sid)),
-            [subject, group, sid] => Ok(Message::Sub(subject, Some(group), sid)),
-            _ => Err(Error::Parse),
+        let (subject, group, sid) = match sub[..] {
+            [subject, sid] => (subject, None, sid),
+            [subject, group, sid] => (subject, Some(group), sid),
+            _ => return Err(Error::Parse),
+        };
+        if !is_valid_subject(subject) {
+            return Err(Error::InvalidSubject);
         }
+        Ok(Message::Sub(subject, group, sid))
     }
 
-    fn connect_message(&self, start: usize, end: usize) -> Result<Message, Error> {
+    fn connect_message(&self, start: usize, end: usize) -> Result<Message<'_>, Error> {
         from_utf8(&self.buff[start..end])
             .map_err(Error::Utf8)
             .and_then(|result| Ok(Message::Connect(serde_json::from_str(result)?)))
     }
 
-    fn pong_message(&self) -> Message {
+    fn pong_message(&self) -> Message<'_> {
         Message::Pong
     }
 
-    fn ping_message(&self) -> Message {
+    fn ping_message(&self) -> Message<'_> {
         Message::Ping
     }
 
     // 这里不能在decode的范围里面使用, 因为其中需要切割buff, 但是decode是返回引用buff的值, 所以应该是编译不过的.
     // 需要使用完decode返回值才调用reset
+    // PUB的消息在pub_complete的时候已经从buff里面切出去了, 不需要再跳过
     pub(super) fn reset(&mut self) {
+        if !matches!(self.state, State::PubPayload) {
+            self.buff.advance(self.end + 1);
+        }
         self.state = State::Start;
-        self.buff.split_to(self.end + 1);
         self.end = 0;
     }
 
-    fn sub_message_complete(&mut self, start: usize, end: usize) -> Result<Poll<Message>, Error> {
+    fn sub_message_complete(&mut self, start: usize, end: usize) -> Result<Poll<Message<'_>>, Error> {
         // 处理订阅
         self.sub_message(start, end).map(Poll::Ready)
     }
@@ -246,68 +419,162 @@ impl Decode {
         &mut self,
         start: usize,
         end: usize,
-    ) -> Result<Poll<Message>, Error> {
+    ) -> Result<Poll<Message<'_>>, Error> {
         self.connect_message(start, end).map(Poll::Ready)
     }
 
-    fn pong_message_complete(&mut self) -> Result<Poll<Message>, Error> {
+    fn pong_message_complete(&mut self) -> Result<Poll<Message<'_>>, Error> {
         Ok(Poll::Ready(self.pong_message()))
     }
 
-    fn ping_message_complete(&mut self) -> Result<Poll<Message>, Error> {
+    fn ping_message_complete(&mut self) -> Result<Poll<Message<'_>>, Error> {
         Ok(Poll::Ready(self.ping_message()))
     }
 
-    fn pub_message(&mut self, start: usize, end: usize) -> Result<Poll<Message>, Error> {
-        let params: Vec<&str> = {
-            from_utf8(&self.buff[start..end])
-                .map_err(Error::Utf8)?
-                .trim()
-                .split("\r\n")
-                .take(2)
-                .collect()
-        };
+    // 按照空格切分控制行的参数, 只记录各个参数的位置
+    fn split_arg(&self, start: usize, end: usize) -> Result<Vec<(usize, usize)>, Error> {
+        let line: &[u8] = &self.buff[start..end];
+        from_utf8(line)?;
+
+        let mut args: Vec<(usize, usize)> = Vec::with_capacity(4);
+        let mut arg_start: Option<usize> = None;
+        for (index, item) in line.iter().enumerate() {
+            let is_space: bool = *item == b' ' || *item == b'\t';
+            match arg_start {
+                Some(position) if is_space => {
+                    args.push((start + position, start + index));
+                    arg_start = None;
+                }
+                None if !is_space => arg_start = Some(index),
+                _ => {}
+            }
+        }
+        if let Some(position) = arg_start {
+            args.push((start + position, end));
+        }
+        Ok(args)
+    }
 
-        match params[..] {
-            [info, content] => {
-                let pub_result: Vec<&str> = info.split_whitespace().take(3).collect();
-                match pub_result[..] {
-                    [subject, content_length] => {
-                        let content_length: usize =
-                            usize::from_str(content_length).map_err(|_| Error::Parse)?;
+    fn parse_size(&self, size: (usize, usize)) -> Result<usize, Error> {
+        from_utf8(&self.buff[size.0..size.1])
+            .ok()
+            .and_then(|size| usize::from_str(size).ok())
+            .ok_or(Error::Parse)
+    }
 
-                        if content_length == content.len() {
-                            Ok(Poll::Ready(Message::Pub(subject, None, content)))
-                        } else {
-                            Err(Error::Parse)
-                        }
-                    }
-                    [subject, replay, content_length] => {
-                        let content_length: usize =
-                            usize::from_str(content_length).map_err(|_| Error::Parse)?;
+    // 解析 PUB <subject> [reply-to] <#bytes>
+    // 只记录各个参数的位置, 真正的payload要等到字节流足够长的时候才返回
+    fn pub_arg(&mut self, start: usize, end: usize) -> Result<(), Error> {
+        let args: Vec<(usize, usize)> = self.split_arg(start, end)?;
+        let (subject, reply_to, size) = match args[..] {
+            [subject, size] => (subject, None, size),
+            [subject, reply_to, size] => (subject, Some(reply_to), size),
+            _ => return Err(Error::Parse),
+        };
+        let size: usize = self.parse_size(size)?;
+        self.set_pub_arg(subject, reply_to, None, size)
+    }
 
-                        if content_length == content.len() {
-                            Ok(Poll::Ready(Message::Pub(subject, Some(replay), content)))
-                        } else {
-                            Err(Error::Parse)
-                        }
-                    }
-                    _ => Err(Error::Parse),
-                }
-            }
-            _ => Err(Error::Parse),
+    // 解析 HPUB <subject> [reply-to] <#header bytes> <#total bytes>
+    fn hpub_arg(&mut self, start: usize, end: usize) -> Result<(), Error> {
+        let args: Vec<(usize, usize)> = self.split_arg(start, end)?;
+        let (subject, reply_to, header_size, size) = match args[..] {
+            [subject, header_size, size] => (subject, None, header_size, size),
+            [subject, reply_to, header_size, size] => (subject, Some(reply_to), header_size, size),
+            _ => return Err(Error::Parse),
+        };
+        let header_size: usize = self.parse_size(header_size)?;
+        let size: usize = self.parse_size(size)?;
+        if header_size > size {
+            return Err(Error::Parse);
         }
+        self.set_pub_arg(subject, reply_to, Some(header_size), size)
     }
 
-    fn pub_complete(&mut self, start: usize, end: usize) -> Result<Poll<Message>, Error> {
-        self.pub_message(start, end)
+    fn set_pub_arg(
+        &mut self,
+        subject: (usize, usize),
+        reply_to: Option<(usize, usize)>,
+        header_size: Option<usize>,
+        size: usize,
+    ) -> Result<(), Error> {
+        // 在等待payload之前就检查长度, 不用等到收完整个payload
+        if size > self.max_payload {
+            return Err(Error::MaxPayload);
+        }
+
+        self.pub_arg = PubArg {
+            subject,
+            reply_to,
+            payload_start: self.end + 1,
+            header_size,
+            size,
+        };
+        Ok(())
     }
 
-    fn unsub_message(&mut self, start: usize, end: usize) -> Result<Poll<Message>, Error> {
+    fn pub_complete(&mut self) -> Result<Poll<Message<'_>>, Error> {
+        let payload_end: usize = self.pub_arg.payload_start + self.pub_arg.size;
+
+        // payload后面跟着的可能是\r\n, 也可能只有\n
+        match self.buff.get(payload_end) {
+            None => return Ok(Poll::Pending),
+            Some(b'\n') => self.end = payload_end,
+            Some(b'\r') => match self.buff.get(payload_end + 1) {
+                None => return Ok(Poll::Pending),
+                Some(b'\n') => self.end = payload_end + 1,
+                Some(_) => return Err(Error::Parse),
+            },
+            Some(_) => return Err(Error::Parse),
+        }
+
+        let PubArg {
+            subject,
+            reply_to,
+            payload_start,
+            header_size,
+            ..
+        } = self.pub_arg;
+        // 把整个消息从buff里面切出来, payload交给订阅者之后, 在所有订阅者发送完之前这块内存都不会被释放
+        // 和buff共享内存的话, 一个很小的payload也会让整块读取缓冲区不能释放,
+        // 慢消费者实际占用的内存会远远超过max_pending, 所以比较短的消息复制一份
+        // 比较长的消息本身就占了缓冲区的大部分, 直接共享不会多占多少内存
+        let frame_len: usize = self.end + 1;
+        self.frame = if frame_len <= COPY_FRAME_MAX {
+            let frame: Bytes = Bytes::copy_from_slice(&self.buff[..frame_len]);
+            self.buff.advance(frame_len);
+            frame
+        } else {
+            self.buff.split_to(frame_len).freeze()
+        };
+
+        // 控制行在pub_arg里面已经检查过utf8了
+        let subject: &str = from_utf8(&self.frame[subject.0..subject.1])?;
+        let reply_to: Option<&str> = match reply_to {
+            Some(reply_to) => Some(from_utf8(&self.frame[reply_to.0..reply_to.1])?),
+            None => None,
+        };
+
+        match header_size {
+            Some(header_size) => Ok(Poll::Ready(Message::HPub(
+                subject,
+                reply_to,
+                self.frame.slice(payload_start..payload_start + header_size),
+                self.frame.slice(payload_start + header_size..payload_end),
+            ))),
+            None => Ok(Poll::Ready(Message::Pub(
+                subject,
+                reply_to,
+                self.frame.slice(payload_start..payload_end),
+            ))),
+        }
+    }
+
+    fn unsub_message(&mut self, start: usize, end: usize) -> Result<Poll<Message<'_>>, Error> {
         from_utf8(&self.buff[start..end])
             .map_err(Error::Utf8)
             .and_then(|unsub_message| {
-                let result: Vec<&str> = unsub_message.trim().split_whitespace().take(2).collect();
+                let result: Vec<&str> = unsub_message.split_whitespace().take(2).collect();
 
                 match result[..] {
                     [sid] => Ok(Poll::Ready(Message::UnSub(sid, None))),
@@ -323,7 +590,7 @@ impl Decode {
             })
     }
 
-    fn unsub_complete(&mut self, start: usize, end: usize) -> Result<Poll<Message>, Error> {
+    fn unsub_complete(&mut self, start: usize, end: usize) -> Result<Poll<Message<'_>>, Error> {
         self.unsub_message(start, end)
     }
 }
@@ -358,7 +625,45 @@ fn decode_pong() {
 
     // 区分大小写
     decode.set_buff(b"pong\r\n");
-    assert_eq!(decode.decode().is_err(), true);
+    assert!(decode.decode().is_err());
+}
+
+#[test]
+fn decode_error() {
+    let mut decode = Decode::new(512);
+
+    // 不认识的协议要断开连接
+    decode.set_buff(b"HELLO\r\n");
+    match decode.decode() {
+        Err(e) => assert!(e.is_fatal()),
+        _ => panic!("message parse error"),
+    }
+
+    // 一整行的长度都不够的话, 不能一直等待
+    let mut decode = Decode::new(512);
+    decode.set_buff(b"OK\r\n");
+    match decode.decode() {
+        Err(Error::UnknownProtocol) => {}
+        _ => panic!("message parse error"),
+    }
+
+    // subject不合法的话, 丢掉这一行之后还可以继续解析
+    let mut decode = Decode::new(512);
+    decode.set_buff(b"SUB foo.*bar 1\r\nSUB foo.* 2\r\n");
+    match decode.decode() {
+        Err(e) => assert!(!e.is_fatal()),
+        _ => panic!("message parse error"),
+    }
+    decode.reset();
+
+    if let Ok(Poll::Ready(Message::Sub(subject, group, sid))) = decode.decode() {
+        assert_eq!(subject, "foo.*");
+        assert!(group.is_none());
+        assert_eq!(sid, "2");
+    } else {
+        panic!("message parse error");
+    }
+    decode.reset();
 }
 
 #[test]
@@ -401,22 +706,14 @@ fn decode_connect_windows() {
     let result = decode.decode();
 
     if let Ok(Poll::Ready(Message::Connect(message))) = result {
-        // assert_eq!(message, Connect {
-        //     name: "#rustlang".to_string(),
-        //     verbose: true,
-        //     ssl_required: false,
-        //     auth_required: false,
-        //     user: None,
-        //     pass: None,
-        //     lang: String::new(),
-        //     version: String::new(),
-        // });
         assert_eq!(
             message,
-            serde_json::from_str::<serde_json::Value>(
-                "{\"name\":\"#rustlang\",\"pedantic\":false,\"verbose\":true}"
-            )
-            .unwrap()
+            Connect {
+                name: Some("#rustlang".to_string()),
+                pedantic: Some(false),
+                verbose: Some(true),
+                ..Connect::default()
+            }
         );
     } else {
         panic!("message parse error");
@@ -433,22 +730,14 @@ fn decode_connect_linux() {
     let result = decode.decode();
 
     if let Ok(Poll::Ready(Message::Connect(message))) = result {
-        // assert_eq!(message, Connect {
-        //     name: "#rustlang".to_string(),
-        //     verbose: true,
-        //     ssl_required: false,
-        //     auth_required: false,
-        //     user: None,
-        //     pass: None,
-        //     lang: String::new(),
-        //     version: String::new(),
-        // });
         assert_eq!(
             message,
-            serde_json::from_str::<serde_json::Value>(
-                "{\"name\":\"#rustlang\",\"pedantic\":false,\"verbose\":true}"
-            )
-            .unwrap()
+            Connect {
+                name: Some("#rustlang".to_string()),
+                pedantic: Some(false),
+                verbose: Some(true),
+                ..Connect::default()
+            }
         );
     } else {
         panic!("message parse error");
@@ -470,22 +759,14 @@ fn decode_connect_chunks() {
     let result = decode.decode();
 
     if let Ok(Poll::Ready(Message::Connect(message))) = result {
-        // assert_eq!(message, Connect {
-        //     name: "#rustlang".to_string(),
-        //     verbose: true,
-        //     ssl_required: false,
-        //     auth_required: false,
-        //     user: None,
-        //     pass: None,
-        //     lang: String::new(),
-        //     version: String::new(),
-        // });
         assert_eq!(
             message,
-            serde_json::from_str::<serde_json::Value>(
-                "{\"name\":\"#rustlang\",\"pedantic\":false,\"verbose\":true}"
-            )
-            .unwrap()
+            Connect {
+                name: Some("#rustlang".to_string()),
+                pedantic: Some(false),
+                verbose: Some(true),
+                ..Connect::default()
+            }
         );
     } else {
         panic!("message parse error");
@@ -502,6 +783,45 @@ fn decode_connect_chunks() {
     decode.reset();
 }
 
+#[test]
+fn decode_connect_options() {
+    let mut decode = Decode::new(512);
+
+    // 不认识的字段直接忽略, 没有发送的字段都是None
+    decode.set_buff(b"CONNECT {\"verbose\":false,\"pedantic\":true,\"tls_required\":false,\"lang\":\"go\",\"version\":\"1.10.0\",\"protocol\":1,\"echo\":false,\"headers\":true,\"no_responders\":true}\r\n");
+
+    if let Ok(Poll::Ready(Message::Connect(message))) = decode.decode() {
+        assert_eq!(
+            message,
+            Connect {
+                verbose: Some(false),
+                pedantic: Some(true),
+                tls_required: Some(false),
+                lang: Some("go".to_string()),
+                version: Some("1.10.0".to_string()),
+                protocol: Some(1),
+                echo: Some(false),
+                headers: Some(true),
+                ..Connect::default()
+            }
+        );
+        assert!(!message.is_verbose());
+        assert!(message.is_pedantic());
+        assert!(message.get_name().is_none());
+    } else {
+        panic!("message parse error");
+    }
+
+    decode.reset();
+
+    // 字段的类型不对的话是解析错误
+    decode.set_buff(b"CONNECT {\"verbose\":\"yes\"}\r\n");
+    match decode.decode() {
+        Err(e) => assert!(e.is_fatal()),
+        _ => panic!("message parse error"),
+    }
+}
+
 #[test]
 #[should_panic]
 fn decode_connect_error() {
@@ -511,22 +831,14 @@ fn decode_connect_error() {
     let result = decode.decode();
 
     if let Ok(Poll::Ready(Message::Connect(message))) = result {
-        // assert_eq!(message, Connect {
-        //     name: "#rustlang".to_string(),
-        //     verbose: true,
-        //     ssl_required: false,
-        //     auth_required: false,
-        //     user: None,
-        //     pass: None,
-        //     lang: String::new(),
-        //     version: String::new(),
-        // });
         assert_eq!(
             message,
-            serde_json::from_str::<serde_json::Value>(
-                "{\"name\":\"#rustlang\",\"pedantic\":false,\"verbose\":true}"
-            )
-            .unwrap()
+            Connect {
+                name: Some("#rustlang".to_string()),
+                pedantic: Some(false),
+                verbose: Some(true),
+                ..Connect::default()
+            }
         );
     } else {
         panic!("message parse error");
@@ -612,7 +924,7 @@ fn decode_sub_protocol_error() {
     // 协议不对
     decode.set_buff(b"sub asdfasd sdfds sdfaf\n");
     let result = decode.decode();
-    result.unwrap();
+    let _ = result.unwrap();
 
     decode.reset();
 }
@@ -624,7 +936,7 @@ fn decode_sub_error() {
     // 格式不对
     decode.set_buff(b"SUB asdfasd asdfasdf sdfds sdfaf\n");
     let result = decode.decode();
-    result.unwrap();
+    let _ = result.unwrap();
 
     decode.reset();
 }
@@ -638,7 +950,7 @@ fn decode_pub_message() {
     if let Ok(Poll::Ready(Message::Pub(subject, reply, content))) = result {
         assert_eq!(subject, "FOO");
         assert_eq!(reply, None);
-        assert_eq!(content, "Hello NATS!");
+        assert_eq!(content, &b"Hello NATS!"[..]);
     } else {
         panic!("message parse error");
     }
@@ -651,7 +963,7 @@ fn decode_pub_message() {
     if let Ok(Poll::Ready(Message::Pub(subject, reply, content))) = result {
         assert_eq!(subject, "FOO");
         assert_eq!(reply, Some("sdfsa"));
-        assert_eq!(content, "Hello World");
+        assert_eq!(content, &b"Hello World"[..]);
     } else {
         panic!("message parse error");
     }
@@ -663,7 +975,7 @@ fn decode_pub_message() {
     if let Ok(Poll::Ready(Message::Pub(subject, reply, content))) = result {
         assert_eq!(subject, "F=");
         assert_eq!(reply, None);
-        assert_eq!(content, "Hello World!");
+        assert_eq!(content, &b"Hello World!"[..]);
     } else {
         panic!("message parse error");
     }
@@ -678,7 +990,7 @@ fn decode_pub_message() {
     if let Ok(Poll::Ready(Message::Pub(subject, reply, content))) = result {
         assert_eq!(subject, "FOO");
         assert_eq!(reply, None);
-        assert_eq!(content, "Hello NATS!");
+        assert_eq!(content, &b"Hello NATS!"[..]);
     } else {
         panic!("message parse error");
     }
@@ -716,3 +1028,239 @@ fn decode_unsub_message() {
 
     decode.reset();
 }
+
+#[test]
+fn decode_pub_binary() {
+    let mut decode = Decode::new(512);
+
+    // payload不一定是utf8
+    decode.set_buff(b"PUB FOO 4\r\n\xff\x00\xfe\x01\r\n");
+    let result = decode.decode();
+
+    if let Ok(Poll::Ready(Message::Pub(subject, reply, content))) = result {
+        assert_eq!(subject, "FOO");
+        assert_eq!(reply, None);
+        assert_eq!(content, &b"\xff\x00\xfe\x01"[..]);
+    } else {
+        panic!("message parse error");
+    }
+
+    decode.reset();
+}
+
+#[test]
+fn decode_pub_copy_small_frame() {
+    let mut decode = Decode::new(64 * 1024);
+    let mut message: Vec<u8> = b"PUB FOO 2\r\nhi\r\n".to_vec();
+    message.extend_from_slice(format!("PUB BAR {}\r\n", COPY_FRAME_MAX).as_bytes());
+    message.extend_from_slice(&vec![b'a'; COPY_FRAME_MAX]);
+    message.extend_from_slice(b"\r\n");
+    decode.set_buff(&message);
+    let start: usize = decode.buff.as_ptr() as usize;
+    let in_buff = |content: &Bytes| (start..start + message.len()).contains(&(content.as_ptr() as usize));
+
+    // 短的消息复制出来, 不会占着读取缓冲区
+    if let Ok(Poll::Ready(Message::Pub(_, _, content))) = decode.decode() {
+        assert_eq!(content, &b"hi"[..]);
+        assert!(!in_buff(&content));
+    } else {
+        panic!("message parse error");
+    }
+    decode.reset();
+
+    // 长的消息直接共享
+    if let Ok(Poll::Ready(Message::Pub(_, _, content))) = decode.decode() {
+        assert_eq!(content.len(), COPY_FRAME_MAX);
+        assert!(in_buff(&content));
+    } else {
+        panic!("message parse error");
+    }
+    decode.reset();
+}
+
+#[test]
+fn decode_pub_crlf_payload() {
+    let mut decode = Decode::new(512);
+
+    // payload里面带有换行符, 只能按照长度来读取
+    decode.set_buff(b"PUB FOO reply 13\r\nHello\r\nWorld\n\r\nPING\r\n");
+    let result = decode.decode();
+
+    if let Ok(Poll::Ready(Message::Pub(subject, reply, content))) = result {
+        assert_eq!(subject, "FOO");
+        assert_eq!(reply, Some("reply"));
+        assert_eq!(content, &b"Hello\r\nWorld\n"[..]);
+    } else {
+        panic!("message parse error");
+    }
+
+    decode.reset();
+
+    let result = decode.decode();
+    if let Ok(Poll::Ready(message)) = result {
+        assert_eq!(message, Message::Ping);
+    } else {
+        panic!("message parse error");
+    }
+
+    decode.reset();
+
+    // 长度对不上
+    decode.set_buff(b"PUB FOO 3\r\nHello\r\n");
+    assert!(decode.decode().is_err());
+}
+
+#[test]
+fn decode_pub_chunks() {
+    let mut decode = Decode::new(512);
+    let message: &[u8] = b"PUB FOO 13\r\nHello\r\n\x00NATS!\r\n";
+
+    // 一个字节一个字节地接收
+    for item in message[..message.len() - 1].chunks(1) {
+        decode.set_buff(item);
+        assert!(decode.decode().unwrap().is_pending());
+    }
+    decode.set_buff(&message[message.len() - 1..]);
+    let result = decode.decode();
+
+    if let Ok(Poll::Ready(Message::Pub(subject, reply, content))) = result {
+        assert_eq!(subject, "FOO");
+        assert_eq!(reply, None);
+        assert_eq!(content, &b"Hello\r\n\x00NATS!"[..]);
+    } else {
+        panic!("message parse error");
+    }
+
+    decode.reset();
+
+    // 一次接收多个payload不完整的消息
+    decode.set_buff(b"PUB FOO 5\r\nHel");
+    assert!(decode.decode().unwrap().is_pending());
+    decode.set_buff(b"lo\r\nPUB BAR 2\r\n");
+    if let Ok(Poll::Ready(Message::Pub(subject, _, content))) = decode.decode() {
+        assert_eq!(subject, "FOO");
+        assert_eq!(content, &b"Hello"[..]);
+    } else {
+        panic!("message parse error");
+    }
+    decode.reset();
+
+    assert!(decode.decode().unwrap().is_pending());
+    decode.set_buff(b"\n\n\r\n");
+    if let Ok(Poll::Ready(Message::Pub(subject, _, content))) = decode.decode() {
+        assert_eq!(subject, "BAR");
+        assert_eq!(content, &b"\n\n"[..]);
+    } else {
+        panic!("message parse error");
+    }
+    decode.reset();
+}
+
+#[test]
+fn decode_limits() {
+    // payload的长度在控制行解析完之后马上检查, 不用等payload收完
+    let mut decode = Decode::new(512).set_max_payload(8);
+    decode.set_buff(b"PUB foo 8\r\n12345678\r\n");
+    if let Ok(Poll::Ready(Message::Pub(_, _, content))) = decode.decode() {
+        assert_eq!(content, &b"12345678"[..]);
+    } else {
+        panic!("message parse error");
+    }
+    decode.reset();
+
+    decode.set_buff(b"PUB foo 9\r\n");
+    match decode.decode() {
+        Err(e @ Error::MaxPayload) => assert!(e.is_fatal()),
+        _ => panic!("message parse error"),
+    }
+
+    // 控制行太长
+    let mut decode = Decode::new(512).set_max_control_line(16);
+    decode.set_buff(b"SUB foo.bar.baz.qux 1\r\n");
+    match decode.decode() {
+        Err(e @ Error::MaxControlLine) => assert!(e.is_fatal()),
+        _ => panic!("message parse error"),
+    }
+
+    // 一直没有换行符的话, 超过长度也要返回错误
+    let mut decode = Decode::new(512).set_max_control_line(16);
+    decode.set_buff(b"PUB foo.bar.");
+    assert!(decode.decode().unwrap().is_pending());
+    decode.set_buff(b"baz.qux");
+    match decode.decode() {
+        Err(Error::MaxControlLine) => {}
+        _ => panic!("message parse error"),
+    }
+}
+
+#[test]
+fn decode_hpub() {
+    let mut decode = Decode::new(512);
+
+    decode.set_buff(b"HPUB foo 12 14\r\nNATS/1.0\r\n\r\nhi\r\n");
+    if let Ok(Poll::Ready(message)) = decode.decode() {
+        assert_eq!(message, Message::HPub(
+                "foo",
+                None,
+                Bytes::from_static(b"NATS/1.0\r\n\r\n"),
+                Bytes::from_static(b"hi")
+            ));
+    } else {
+        panic!("message parse error");
+    }
+    decode.reset();
+
+    // 带reply_to, 并且分开接收
+    decode.set_buff(b"HPUB foo INBOX.1 18 ");
+    assert!(decode.decode().unwrap().is_pending());
+    decode.set_buff(b"23\r\nNATS/1.0\r\nA: 1\r\n\r\nhel");
+    assert!(decode.decode().unwrap().is_pending());
+    decode.set_buff(b"lo\r\n");
+    if let Ok(Poll::Ready(message)) = decode.decode() {
+        assert_eq!(
+            message,
+            Message::HPub(
+                "foo",
+                Some("INBOX.1"),
+                Bytes::from_static(b"NATS/1.0\r\nA: 1\r\n\r\n"),
+                Bytes::from_static(b"hello")
+            )
+        );
+    } else {
+        panic!("message parse error");
+    }
+    decode.reset();
+
+    // header的长度不能超过总长度
+    decode.set_buff(b"HPUB foo 14 12\r\n");
+    assert!(decode.decode().is_err());
+
+    let mut decode = Decode::new(512);
+    decode.set_buff(b"HPUBfoo 12 14\r\n");
+    assert!(decode.decode().is_err());
+}
+
+#[test]
+fn decode_pub_shared_payload() {
+    let mut decode = Decode::new(16);
+
+    // payload从buff里面切出来之后, 继续接收数据也不会影响已经交出去的payload
+    decode.set_buff(b"PUB foo 5\r\nHello\r\nPUB bar 5\r\n");
+    let payload: Bytes = match decode.decode() {
+        Ok(Poll::Ready(Message::Pub(_, _, content))) => content,
+        _ => panic!("message parse error"),
+    };
+    decode.reset();
+
+    assert!(decode.decode().unwrap().is_pending());
+    decode.set_buff(b"World\r\n");
+    if let Ok(Poll::Ready(Message::Pub(subject, _, content))) = decode.decode() {
+        assert_eq!(subject, "bar");
+        assert_eq!(content, &b"World"[..]);
+    } else {
+        panic!("message parse error");
+    }
+    decode.reset();
+
+    assert_eq!(payload, &b"Hello"[..]);
+}
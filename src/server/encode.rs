@@ -1,11 +1,17 @@
+use super::decode::Error;
+use bytes::Bytes;
 use serde_derive::Serialize;
 use serde_json::error::Result;
+use std::borrow::Cow;
 use std::default::Default;
 use std::net::IpAddr;
 
 const PING: &[u8; 6] = b"PING\r\n";
 const PONG: &[u8; 6] = b"PONG\r\n";
 const OK: &[u8; 5] = b"+OK\r\n";
+const CRLF: &[u8; 2] = b"\r\n";
+// 慢消费者是写入的时候发现的, 不是解析协议的错误
+const SLOW_CONSUMER: &[u8; 22] = b"-ERR 'Slow Consumer'\r\n";
 
 #[derive(Debug, Serialize)]
 pub(super) struct Info {
@@ -16,8 +22,11 @@ pub(super) struct Info {
     port: u16,
     auth_required: bool,
     ssl_required: bool,
+    tls_required: bool,
     max_payload: usize,
     proto: usize,
+    headers: bool,
+    nonce: String,
     client_id: usize,
     client_ip: String,
     git_commit: String,
@@ -64,6 +73,11 @@ impl Info {
         self
     }
 
+    pub(super) fn set_tls_required(mut self, tls_required: bool) -> Self {
+        self.tls_required = tls_required;
+        self
+    }
+
     pub(super) fn set_max_payload(mut self, max_payload: usize) -> Self {
         self.max_payload = max_payload;
         self
@@ -74,6 +88,16 @@ impl Info {
         self
     }
 
+    pub(super) fn set_headers(mut self, headers: bool) -> Self {
+        self.headers = headers;
+        self
+    }
+
+    pub(super) fn set_nonce(mut self, nonce: String) -> Self {
+        self.nonce = nonce;
+        self
+    }
+
     pub(super) fn set_client_id(mut self, client_id: usize) -> Self {
         self.client_id = client_id;
         self
@@ -99,8 +123,11 @@ impl Default for Info {
             port: 8090,
             auth_required: false,
             ssl_required: false,
+            tls_required: false,
             max_payload: 512,
             proto: 1,
+            headers: false,
+            nonce: String::new(),
             client_id: 0,
             client_ip: "127.0.0.1".to_string(),
             git_commit: "8c8d6f".to_string(),
@@ -136,30 +163,90 @@ impl ResponseOk {
     }
 }
 
+#[derive(Debug)]
+pub(super) struct ResponseErr;
+
+impl ResponseErr {
+    pub(super) fn format(error: &Error) -> Vec<u8> {
+        let message: Cow<'_, str> = match error {
+            Error::UnknownProtocol => Cow::Borrowed("Unknown Protocol Operation"),
+            Error::Parse | Error::Serde(_) | Error::Utf8(_) => Cow::Borrowed("Parser Error"),
+            Error::InvalidSubject => Cow::Borrowed("Invalid Subject"),
+            Error::InvalidPublishSubject => Cow::Borrowed("Invalid Publish Subject"),
+            Error::StaleConnection => Cow::Borrowed("Stale Connection"),
+            Error::MaxPayload => Cow::Borrowed("Maximum Payload Violation"),
+            Error::MaxControlLine => Cow::Borrowed("Maximum Control Line Exceeded"),
+            Error::AuthorizationViolation => Cow::Borrowed("Authorization Violation"),
+            Error::AuthenticationTimeout => Cow::Borrowed("Authentication Timeout"),
+            Error::AuthenticationExpired => Cow::Borrowed("User Authentication Expired"),
+            Error::PublishViolation(subject) => {
+                Cow::Owned(format!("Permissions Violation for Publish to {}", subject))
+            }
+            Error::SubscriptionViolation(subject) => {
+                Cow::Owned(format!("Permissions Violation for Subscription to {}", subject))
+            }
+            Error::MaxSubscriptions => Cow::Borrowed("Maximum Subscriptions Exceeded"),
+        };
+        format!("-ERR '{}'\r\n", message).into_bytes()
+    }
+
+    pub(super) fn slow_consumer() -> &'static [u8] {
+        SLOW_CONSUMER
+    }
+}
+
+// 发送给订阅者的消息
+// 控制行在发布的时候只生成一次, payload是从解析器里面切出来的Bytes,
+// 每个订阅者拿到的都是同一块内存的引用, 不会复制payload
 #[derive(Debug)]
 pub(super) struct Msg {
-    front_chunk: Vec<u8>,
-    after_chunk: Vec<u8>,
+    front_chunk: Bytes,
+    after_chunk: Bytes,
+    headers: Option<Bytes>,
+    payload: Bytes,
 }
 
+// MSG <subject> <sid> [reply-to] <#bytes>\r\n[payload]\r\n
+// HMSG <subject> <sid> [reply-to] <#header bytes> <#total bytes>\r\n[headers][payload]\r\n
 impl Msg {
-    pub(super) fn new<'a>(subject: &'a str, reply_to: Option<&'a str>, content: &'a str) -> Self {
-        let content_len_str: String = content.len().to_string();
-        let mut front_chunk: Vec<u8> = Vec::with_capacity(4 + subject.as_bytes().len() + 1);
+    pub(super) fn new(subject: &str, reply_to: Option<&str>, payload: Bytes) -> Self {
+        Self::build(b"MSG ", subject, reply_to, None, payload)
+    }
+
+    pub(super) fn with_headers(
+        subject: &str,
+        reply_to: Option<&str>,
+        headers: Bytes,
+        payload: Bytes,
+    ) -> Self {
+        Self::build(b"HMSG ", subject, reply_to, Some(headers), payload)
+    }
+
+    fn build(
+        op: &[u8],
+        subject: &str,
+        reply_to: Option<&str>,
+        headers: Option<Bytes>,
+        payload: Bytes,
+    ) -> Self {
+        let size_str: String = match &headers {
+            Some(headers) => format!("{} {}", headers.len(), headers.len() + payload.len()),
+            None => payload.len().to_string(),
+        };
+        let mut front_chunk: Vec<u8> = Vec::with_capacity(op.len() + subject.len() + 1);
 
-        front_chunk.extend_from_slice(b"MSG ");
+        front_chunk.extend_from_slice(op);
         front_chunk.extend_from_slice(subject.as_bytes());
         front_chunk.extend_from_slice(b" ");
 
         let mut after_chunk: Vec<u8> = Vec::with_capacity(
             {
                 match reply_to {
-                    Some(reply) => reply.as_bytes().len() + 2,
+                    Some(reply) => reply.len() + 2,
                     None => 1,
                 }
-            } + content_len_str.as_bytes().len()
-                + b"\r\n".len() * 2
-                + content.as_bytes().len(),
+            } + size_str.len()
+                + CRLF.len(),
         );
 
         after_chunk.extend_from_slice(b" ");
@@ -167,22 +254,47 @@ impl Msg {
             after_chunk.extend_from_slice(reply.as_bytes());
             after_chunk.extend_from_slice(b" ");
         }
-        after_chunk.extend_from_slice(content_len_str.as_bytes());
-        after_chunk.extend_from_slice(b"\r\n");
-        after_chunk.extend_from_slice(content.as_bytes());
-        after_chunk.extend_from_slice(b"\r\n");
+        after_chunk.extend_from_slice(size_str.as_bytes());
+        after_chunk.extend_from_slice(CRLF);
 
         Self {
-            front_chunk,
-            after_chunk,
+            front_chunk: Bytes::from(front_chunk),
+            after_chunk: Bytes::from(after_chunk),
+            headers,
+            payload,
         }
     }
 
-    pub(super) fn get_front_chunk(&self) -> &[u8] {
-        &self.front_chunk
+    // 发送给一个订阅者的全部数据, 只是增加引用计数
+    // 没有header的时候中间是一个空的Bytes, 写入的时候会被跳过
+    pub(super) fn get_chunks(&self, sid: &Bytes) -> [Bytes; 6] {
+        [
+            self.front_chunk.clone(),
+            sid.clone(),
+            self.after_chunk.clone(),
+            self.headers.clone().unwrap_or_default(),
+            self.payload.clone(),
+            Bytes::from_static(CRLF),
+        ]
     }
+}
 
-    pub(super) fn get_after_chunk(&self) -> &[u8] {
-        &self.after_chunk
-    }
+#[test]
+fn encode_msg() {
+    let concat = |chunks: [Bytes; 6]| chunks.concat();
+    let sid: Bytes = Bytes::from_static(b"1");
+
+    let msg = Msg::new("foo", None, Bytes::from_static(b"hello"));
+    assert_eq!(concat(msg.get_chunks(&sid)), b"MSG foo 1 5\r\nhello\r\n");
+
+    let msg = Msg::with_headers(
+        "foo",
+        Some("bar"),
+        Bytes::from_static(b"NATS/1.0\r\n\r\n"),
+        Bytes::from_static(b"hi"),
+    );
+    assert_eq!(
+        concat(msg.get_chunks(&sid)),
+        b"HMSG foo 1 bar 12 14\r\nNATS/1.0\r\n\r\nhi\r\n"
+    );
 }
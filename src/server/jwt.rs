@@ -0,0 +1,601 @@
+use super::permissions::{Permission, Permissions, ResponsePermission};
+use crate::config::{OperatorConfig, ResolverConfig};
+use data_encoding::{DecodeError, BASE64URL_NOPAD};
+use nkeys::error::Error as NkeyError;
+use nkeys::KeyPair;
+use serde::de::DeserializeOwned;
+use serde_derive::Deserialize;
+use serde_json::error::Error as SerdeError;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs::read_to_string;
+use std::io::Error as IoError;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+// 和nats一样, v2的JWT用ed25519-nkey, 兼容一下v1的ed25519
+const ALGORITHMS: [&str; 2] = ["ed25519-nkey", "ed25519"];
+
+const OPERATOR_CLAIM: &str = "operator";
+const ACCOUNT_CLAIM: &str = "account";
+const USER_CLAIM: &str = "user";
+
+// 账户的revocations里面用来吊销所有用户的key
+const ALL_USERS: &str = "*";
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("io error `{0}`")]
+    Io(#[from] IoError),
+
+    #[error("invalid jwt format")]
+    Format,
+
+    #[error("base64 decode error `{0}`")]
+    Base64(#[from] DecodeError),
+
+    #[error("serde json error `{0}`")]
+    Serde(#[from] SerdeError),
+
+    #[error("nkey error `{0}`")]
+    Nkey(#[from] NkeyError),
+
+    #[error("unsupported algorithm `{0}`")]
+    Algorithm(String),
+
+    #[error("expected {0} claims")]
+    ClaimType(&'static str),
+
+    #[error("jwt expired")]
+    Expired,
+
+    #[error("jwt not valid yet")]
+    NotYetValid,
+
+    #[error("untrusted issuer `{0}`")]
+    Untrusted(String),
+
+    #[error("account `{0}` not found")]
+    AccountNotFound(String),
+
+    #[error("user `{0}` revoked")]
+    Revoked(String),
+}
+
+#[derive(Debug, Deserialize)]
+struct Header {
+    alg: String,
+}
+
+// 所有JWT共同的部分, nats里面是各种类型自己的内容
+#[derive(Debug, Deserialize)]
+pub(super) struct Claims<T> {
+    #[serde(default)]
+    iat: u64,
+    iss: String,
+    sub: String,
+    #[serde(default)]
+    name: String,
+    exp: Option<u64>,
+    nbf: Option<u64>,
+    nats: Nats<T>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Nats<T> {
+    #[serde(rename = "type")]
+    claim_type: String,
+    #[serde(flatten)]
+    claims: T,
+}
+
+impl<T> Claims<T> {
+    pub(super) fn get_subject(&self) -> &str {
+        &self.sub
+    }
+
+    pub(super) fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    // 过期时间太大, SystemTime表示不了的话当作不会过期
+    pub(super) fn get_expires(&self) -> Option<SystemTime> {
+        self.exp
+            .and_then(|exp| UNIX_EPOCH.checked_add(Duration::from_secs(exp)))
+    }
+
+    pub(super) fn get_claims(&self) -> &T {
+        &self.nats.claims
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct OperatorClaims {
+    #[serde(default)]
+    signing_keys: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct AccountClaims {
+    #[serde(default)]
+    signing_keys: Vec<SigningKey>,
+    // 用户的公钥对应吊销的时间, 在这之前签发的用户JWT都不能用
+    #[serde(default)]
+    revocations: HashMap<String, u64>,
+}
+
+// 账户的签名key, 带有scope的key签发的用户只能使用scope里面的权限和限制
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum SigningKey {
+    Key(String),
+    Scoped { key: String, template: UserLimits },
+}
+
+impl SigningKey {
+    fn get_key(&self) -> &str {
+        match self {
+            SigningKey::Key(key) => key,
+            SigningKey::Scoped { key, .. } => key,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+struct PermissionClaims {
+    #[serde(default)]
+    allow: Vec<String>,
+    #[serde(default)]
+    deny: Vec<String>,
+}
+
+impl PermissionClaims {
+    fn to_permission(&self) -> Permission {
+        Permission::new(self.allow.clone(), self.deny.clone())
+    }
+}
+
+// 回复的权限, ttl的单位是纳秒
+#[derive(Debug, Default, Clone, Deserialize)]
+struct ResponseClaims {
+    #[serde(default)]
+    max: i64,
+    #[serde(default)]
+    ttl: i64,
+}
+
+impl ResponseClaims {
+    fn to_permission(&self) -> ResponsePermission {
+        let expires: Option<Duration> = u64::try_from(self.ttl)
+            .ok()
+            .filter(|ttl| *ttl > 0)
+            .map(Duration::from_nanos);
+        ResponsePermission::new(usize::try_from(self.max).unwrap_or_default(), expires)
+    }
+}
+
+// 用户的权限和限制, 小于0的限制表示不限制
+#[derive(Debug, Clone, Deserialize)]
+pub(super) struct UserLimits {
+    #[serde(default, rename = "pub")]
+    publish: PermissionClaims,
+    #[serde(default, rename = "sub")]
+    subscribe: PermissionClaims,
+    resp: Option<ResponseClaims>,
+    #[serde(default = "no_limit")]
+    subs: i64,
+    #[serde(default = "no_limit")]
+    payload: i64,
+    // bearer的JWT不需要对nonce签名
+    #[serde(default)]
+    bearer_token: bool,
+}
+
+fn no_limit() -> i64 {
+    -1
+}
+
+impl UserLimits {
+    pub(super) fn get_permissions(&self) -> Permissions {
+        Permissions::new(self.publish.to_permission(), self.subscribe.to_permission())
+            .set_responses(self.resp.as_ref().map(ResponseClaims::to_permission))
+    }
+
+    pub(super) fn get_max_subs(&self) -> Option<usize> {
+        usize::try_from(self.subs).ok()
+    }
+
+    pub(super) fn get_max_payload(&self) -> Option<usize> {
+        usize::try_from(self.payload).ok()
+    }
+
+    pub(super) fn is_bearer_token(&self) -> bool {
+        self.bearer_token
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct UserClaims {
+    // 用户由账户的签名key签发的时候, 这里是账户的公钥
+    issuer_account: Option<String>,
+    #[serde(flatten)]
+    limits: UserLimits,
+}
+
+impl UserClaims {
+    pub(super) fn get_limits(&self) -> &UserLimits {
+        &self.limits
+    }
+}
+
+// 解析JWT, 并且用iss的公钥检查签名
+fn decode<T: DeserializeOwned>(token: &str, claim_type: &'static str) -> Result<Claims<T>, Error> {
+    let token: &str = token.trim();
+    let mut parts = token.rsplitn(2, '.');
+    let (signature, message) = match (parts.next(), parts.next()) {
+        (Some(signature), Some(message)) => (signature, message),
+        _ => return Err(Error::Format),
+    };
+    let (header, payload) = match message.split_once('.') {
+        Some((header, payload)) if !payload.contains('.') => (header, payload),
+        _ => return Err(Error::Format),
+    };
+
+    let header: Header = serde_json::from_slice(&BASE64URL_NOPAD.decode(header.as_bytes())?)?;
+    if !ALGORITHMS.contains(&header.alg.to_lowercase().as_str()) {
+        return Err(Error::Algorithm(header.alg));
+    }
+    let claims: Claims<T> = serde_json::from_slice(&BASE64URL_NOPAD.decode(payload.as_bytes())?)?;
+    if claims.nats.claim_type != claim_type {
+        return Err(Error::ClaimType(claim_type));
+    }
+
+    let signature: Vec<u8> = BASE64URL_NOPAD.decode(signature.as_bytes())?;
+    KeyPair::from_public_key(&claims.iss)?.verify(message.as_bytes(), &signature)?;
+
+    let now: u64 = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs())
+        .unwrap_or_default();
+    if claims.exp.is_some_and(|exp| exp <= now) {
+        return Err(Error::Expired);
+    }
+    if claims.nbf.is_some_and(|nbf| nbf > now) {
+        return Err(Error::NotYetValid);
+    }
+    Ok(claims)
+}
+
+// 公钥的第一个字符表示key的类型
+fn is_public_key(key: &str, prefix: char) -> bool {
+    key.starts_with(prefix) && KeyPair::from_public_key(key).is_ok()
+}
+
+// 根据账户的公钥找到账户的JWT
+#[derive(Debug)]
+enum Resolver {
+    Memory(HashMap<String, String>),
+    Dir(PathBuf),
+}
+
+impl Resolver {
+    async fn fetch(&self, account: &str) -> Result<String, Error> {
+        match self {
+            Resolver::Memory(accounts) => accounts
+                .get(account)
+                .cloned()
+                .ok_or_else(|| Error::AccountNotFound(account.to_string())),
+            Resolver::Dir(dir) => {
+                // 账户的公钥已经检查过了, 不会跑到目录外面
+                let path: PathBuf = dir.join(format!("{}.jwt", account));
+                tokio::fs::read_to_string(path)
+                    .await
+                    .map_err(|_| Error::AccountNotFound(account.to_string()))
+            }
+        }
+    }
+}
+
+// 信任的operator, 由它签发的账户JWT再签发用户的JWT
+// operator -> account -> user 每一层都要检查签名
+#[derive(Debug)]
+pub(super) struct Operator {
+    // operator自己的公钥和签名key
+    keys: Vec<String>,
+    resolver: Resolver,
+}
+
+impl Operator {
+    pub(super) fn new(config: &OperatorConfig) -> Result<Self, Error> {
+        let claims: Claims<OperatorClaims> =
+            decode(&read_to_string(config.get_jwt_file())?, OPERATOR_CLAIM)?;
+        // operator的JWT是自己签名的
+        if claims.iss != claims.sub || !is_public_key(&claims.sub, 'O') {
+            return Err(Error::Untrusted(claims.iss));
+        }
+        let mut keys: Vec<String> = claims.nats.claims.signing_keys;
+        keys.push(claims.sub);
+
+        let resolver: Resolver = match config.get_resolver() {
+            ResolverConfig::Memory { accounts } => Resolver::Memory(accounts.clone()),
+            ResolverConfig::Dir { dir } => Resolver::Dir(PathBuf::from(dir)),
+        };
+        let operator: Self = Self { keys, resolver };
+        // 写在配置里面的账户启动的时候就检查
+        if let Resolver::Memory(accounts) = &operator.resolver {
+            for (account, token) in accounts {
+                operator.verify_account(account, token)?;
+            }
+        }
+        Ok(operator)
+    }
+
+    fn verify_account(&self, account: &str, token: &str) -> Result<Claims<AccountClaims>, Error> {
+        let claims: Claims<AccountClaims> = decode(token, ACCOUNT_CLAIM)?;
+        if !self.keys.contains(&claims.iss) {
+            return Err(Error::Untrusted(claims.iss));
+        }
+        if claims.sub != account {
+            return Err(Error::AccountNotFound(account.to_string()));
+        }
+        Ok(claims)
+    }
+
+    // 检查用户JWT的整条签名链, 返回用户的claims和所属的账户
+    // 用户是由带scope的签名key签发的话, 权限和限制换成scope里面的
+    pub(super) async fn verify_user(
+        &self,
+        token: &str,
+    ) -> Result<(Claims<UserClaims>, String), Error> {
+        let mut claims: Claims<UserClaims> = decode(token, USER_CLAIM)?;
+        if !is_public_key(&claims.sub, 'U') {
+            return Err(Error::ClaimType(USER_CLAIM));
+        }
+        let account: String = claims
+            .nats
+            .claims
+            .issuer_account
+            .clone()
+            .unwrap_or_else(|| claims.iss.clone());
+        if !is_public_key(&account, 'A') {
+            return Err(Error::Untrusted(account));
+        }
+
+        let account_claims: Claims<AccountClaims> =
+            self.verify_account(&account, &self.resolver.fetch(&account).await?)?;
+        if claims.iss != account {
+            let signing_key: &SigningKey = account_claims
+                .nats
+                .claims
+                .signing_keys
+                .iter()
+                .find(|key| key.get_key() == claims.iss)
+                .ok_or_else(|| Error::Untrusted(claims.iss.clone()))?;
+            if let SigningKey::Scoped { template, .. } = signing_key {
+                claims.nats.claims.limits = template.clone();
+            }
+        }
+
+        let revocations: &HashMap<String, u64> = &account_claims.nats.claims.revocations;
+        let revoked: bool = [claims.sub.as_str(), ALL_USERS]
+            .iter()
+            .filter_map(|key| revocations.get(*key))
+            .any(|revoked_at| claims.iat <= *revoked_at);
+        if revoked {
+            return Err(Error::Revoked(claims.sub));
+        }
+        Ok((claims, account))
+    }
+}
+
+// 测试用, 用key_pair签发JWT
+#[cfg(test)]
+pub(super) fn test_encode(key_pair: &KeyPair, claims: serde_json::Value) -> String {
+    let header: String = BASE64URL_NOPAD.encode(br#"{"typ":"JWT","alg":"ed25519-nkey"}"#);
+    let payload: String = BASE64URL_NOPAD.encode(claims.to_string().as_bytes());
+    let message: String = format!("{}.{}", header, payload);
+    let signature: Vec<u8> = key_pair.sign(message.as_bytes()).unwrap();
+    format!("{}.{}", message, BASE64URL_NOPAD.encode(&signature))
+}
+
+// 测试用的临时文件或者目录, 离开作用域的时候删除
+#[cfg(test)]
+pub(super) struct TestPath(PathBuf);
+
+#[cfg(test)]
+impl Drop for TestPath {
+    fn drop(&mut self) {
+        let _ = if self.0.is_dir() {
+            std::fs::remove_dir_all(&self.0)
+        } else {
+            std::fs::remove_file(&self.0)
+        };
+    }
+}
+
+// 测试用, 生成operator和账户, 账户放在memory resolver里面
+// operator的JWT写在临时文件里面, 返回的TestPath要保留到加载完配置
+#[cfg(test)]
+pub(super) fn test_operator(
+    account: &KeyPair,
+    account_nats: serde_json::Value,
+) -> (OperatorConfig, TestPath) {
+    use serde_json::json;
+
+    let operator: KeyPair = KeyPair::new_operator();
+    let operator_jwt: String = test_encode(
+        &operator,
+        json!({"iss": operator.public_key(), "sub": operator.public_key(), "nats": {"type": "operator"}}),
+    );
+    let mut nats = account_nats;
+    nats["type"] = json!("account");
+    let account_jwt: String = test_encode(
+        &operator,
+        json!({"iss": operator.public_key(), "sub": account.public_key(), "nats": nats}),
+    );
+
+    let jwt_file: PathBuf = std::env::temp_dir().join(format!(
+        "{}-operator.jwt",
+        uuid::Uuid::new_v4().to_simple()
+    ));
+    std::fs::write(&jwt_file, operator_jwt).unwrap();
+    let mut accounts: toml::value::Table = toml::value::Table::new();
+    accounts.insert(account.public_key(), toml::Value::String(account_jwt));
+    let config: OperatorConfig = toml::from_str(&format!(
+        "jwt_file = {:?}\nresolver = {{ type = \"memory\", accounts = {} }}",
+        jwt_file,
+        toml::Value::Table(accounts)
+    ))
+    .unwrap();
+    (config, TestPath(jwt_file))
+}
+
+#[tokio::test]
+async fn jwt_verify_user() {
+    use serde_json::json;
+
+    let account: KeyPair = KeyPair::new_account();
+    let signing_key: KeyPair = KeyPair::new_account();
+    let scoped_key: KeyPair = KeyPair::new_account();
+    let revoked: KeyPair = KeyPair::new_user();
+    let (config, _jwt_file) = test_operator(
+        &account,
+        json!({
+            "signing_keys": [
+                signing_key.public_key(),
+                {"kind": "user_scope", "key": scoped_key.public_key(), "template": {"pub": {"allow": ["scoped.>"]}, "subs": 1}},
+            ],
+            "revocations": {revoked.public_key(): 4102444800u64},
+        }),
+    );
+    let operator: Operator = Operator::new(&config).unwrap();
+    let user: KeyPair = KeyPair::new_user();
+
+    let token: String = test_encode(
+        &account,
+        json!({"iss": account.public_key(), "sub": user.public_key(), "name": "alice", "nats": {
+            "type": "user", "pub": {"allow": ["orders.>"]}, "sub": {"deny": ["secret.>"]}, "subs": 10, "payload": 1024,
+            "resp": {"max": 1, "ttl": 1000000000u64},
+        }}),
+    );
+    let (claims, issuer) = operator.verify_user(&token).await.unwrap();
+    assert_eq!(issuer, account.public_key());
+    assert_eq!(claims.get_subject(), user.public_key());
+    assert_eq!(claims.get_name(), "alice");
+    let limits: &UserLimits = claims.get_claims().get_limits();
+    assert_eq!(limits.get_max_subs(), Some(10));
+    assert_eq!(limits.get_max_payload(), Some(1024));
+    assert!(!limits.is_bearer_token());
+    let permissions: Permissions = limits.get_permissions();
+    assert!(permissions.can_publish("orders.created"));
+    assert!(!permissions.can_publish("payments.created"));
+    assert!(!permissions.can_subscribe("secret.key"));
+    assert!(permissions.get_responses().is_some());
+
+    // 账户的签名key签发的用户
+    let token: String = test_encode(
+        &signing_key,
+        json!({"iss": signing_key.public_key(), "sub": user.public_key(), "nats": {
+            "type": "user", "issuer_account": account.public_key(),
+        }}),
+    );
+    let (claims, _) = operator.verify_user(&token).await.unwrap();
+    assert_eq!(claims.get_claims().get_limits().get_max_subs(), None);
+
+    // 带scope的签名key, 用户自己的权限不起作用
+    let token: String = test_encode(
+        &scoped_key,
+        json!({"iss": scoped_key.public_key(), "sub": user.public_key(), "nats": {
+            "type": "user", "issuer_account": account.public_key(), "pub": {"allow": [">"]},
+        }}),
+    );
+    let (claims, _) = operator.verify_user(&token).await.unwrap();
+    let limits: &UserLimits = claims.get_claims().get_limits();
+    assert_eq!(limits.get_max_subs(), Some(1));
+    assert!(!limits.get_permissions().can_publish("orders.created"));
+    assert!(limits.get_permissions().can_publish("scoped.foo"));
+
+    let other: KeyPair = KeyPair::new_account();
+    for token in &[
+        // 不是账户签发的
+        test_encode(&other, json!({"iss": other.public_key(), "sub": user.public_key(), "nats": {"type": "user"}})),
+        test_encode(&other, json!({"iss": other.public_key(), "sub": user.public_key(), "nats": {
+            "type": "user", "issuer_account": account.public_key(),
+        }})),
+        // 过期了
+        test_encode(&account, json!({"iss": account.public_key(), "sub": user.public_key(), "exp": 1, "nats": {"type": "user"}})),
+        // 被吊销了
+        test_encode(&account, json!({"iss": account.public_key(), "sub": revoked.public_key(), "nats": {"type": "user"}})),
+        // 类型不对
+        test_encode(&account, json!({"iss": account.public_key(), "sub": user.public_key(), "nats": {"type": "account"}})),
+        "abc.def".to_string(),
+    ] {
+        assert!(operator.verify_user(token).await.is_err());
+    }
+
+    // 签名被篡改
+    let token: String = test_encode(
+        &account,
+        json!({"iss": account.public_key(), "sub": user.public_key(), "nats": {"type": "user"}}),
+    );
+    let mut parts: Vec<&str> = token.split('.').collect();
+    let payload: String = BASE64URL_NOPAD.encode(
+        json!({"iss": account.public_key(), "sub": user.public_key(), "nats": {"type": "user", "subs": 100}})
+            .to_string()
+            .as_bytes(),
+    );
+    parts[1] = &payload;
+    assert!(operator.verify_user(&parts.join(".")).await.is_err());
+}
+
+#[test]
+fn jwt_expires_overflow() {
+    use serde_json::json;
+
+    // 过期时间超出SystemTime的范围的时候不能panic
+    let claims: Claims<UserClaims> = serde_json::from_value(
+        json!({"iss": "", "sub": "", "exp": u64::MAX, "nats": {"type": "user"}}),
+    )
+    .unwrap();
+    assert_eq!(claims.get_expires(), None);
+
+    let claims: Claims<UserClaims> =
+        serde_json::from_value(json!({"iss": "", "sub": "", "exp": 60, "nats": {"type": "user"}})).unwrap();
+    assert_eq!(claims.get_expires(), Some(UNIX_EPOCH + Duration::from_secs(60)));
+}
+
+#[tokio::test]
+async fn jwt_dir_resolver() {
+    use serde_json::json;
+
+    let account: KeyPair = KeyPair::new_account();
+    let (config, _jwt_file) = test_operator(&account, json!({}));
+    let token: String = match config.get_resolver() {
+        ResolverConfig::Memory { accounts } => accounts[&account.public_key()].clone(),
+        ResolverConfig::Dir { .. } => unreachable!(),
+    };
+
+    let dir: PathBuf = std::env::temp_dir().join(uuid::Uuid::new_v4().to_simple().to_string());
+    std::fs::create_dir(&dir).unwrap();
+    let _dir: TestPath = TestPath(dir.clone());
+    let config: OperatorConfig = toml::from_str(&format!(
+        "jwt_file = {:?}\nresolver = {{ type = \"dir\", dir = {:?} }}",
+        config.get_jwt_file(),
+        dir
+    ))
+    .unwrap();
+    let operator: Operator = Operator::new(&config).unwrap();
+
+    let user: KeyPair = KeyPair::new_user();
+    let user_jwt: String = test_encode(
+        &account,
+        json!({"iss": account.public_key(), "sub": user.public_key(), "nats": {"type": "user"}}),
+    );
+    // 账户的JWT不存在
+    assert!(operator.verify_user(&user_jwt).await.is_err());
+
+    // 新加的账户不需要重启
+    std::fs::write(dir.join(format!("{}.jwt", account.public_key())), token).unwrap();
+    assert!(operator.verify_user(&user_jwt).await.is_ok());
+}
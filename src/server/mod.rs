@@ -1,10 +1,18 @@
+mod account;
+mod auth;
 mod decode;
 mod encode;
+mod jwt;
+mod permissions;
 mod read_stream;
+#[allow(clippy::module_inception)]
 mod server;
 mod service;
+mod stats;
 mod sub_list;
 mod sub_struct;
+mod tls;
 mod write_stream;
 
 pub use server::Server;
+pub use stats::Stats;
@@ -0,0 +1,411 @@
+use super::sub_list::{is_subject_overlap, is_subset_match, is_valid_subject};
+use crate::config::{PermissionConfig, PermissionsConfig, ResponsePermissionConfig};
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+// 每个连接最多缓存的subject数量, 满了之后随便删掉一部分
+const CACHE_MAX: usize = 128;
+const CACHE_SWEEP: usize = 32;
+
+// 和nats一样, 每个reply默认只能回复一次, 2分钟之后过期
+const DEFAULT_RESPONSE_MAX: usize = 1;
+const DEFAULT_RESPONSE_EXPIRES: Duration = Duration::from_secs(2 * 60);
+// 每个连接最多记录的reply数量, 满了之后先删掉过期的, 还是满的话随便删掉一部分
+const REPLIES_MAX: usize = 4096;
+const REPLIES_SWEEP: usize = 1024;
+
+// 一个方向(发布或者订阅)的权限
+// allow为空的话表示全部允许, deny的优先级比allow高
+#[derive(Debug, Default)]
+pub(super) struct Permission {
+    allow: Vec<String>,
+    deny: Vec<String>,
+}
+
+impl Permission {
+    pub(super) fn new(allow: Vec<String>, deny: Vec<String>) -> Self {
+        Self { allow, deny }
+    }
+
+    fn from_config(config: &PermissionConfig) -> Self {
+        Self::new(config.get_allow().to_vec(), config.get_deny().to_vec())
+    }
+
+    // 返回第一个不合法的subject
+    fn find_invalid(&self) -> Option<&str> {
+        self.allow
+            .iter()
+            .chain(self.deny.iter())
+            .find(|subject| !is_valid_subject(subject))
+            .map(String::as_str)
+    }
+
+    // subject带通配符的话, 能匹配到的subject都要被允许, 并且不能匹配到任何一个禁止的subject
+    // 比如禁止了secret.*, 订阅>或者*.key也会收到secret.key, 所以也要禁止
+    pub(super) fn is_allowed(&self, subject: &str) -> bool {
+        let allowed: bool = self.allow.is_empty()
+            || self
+                .allow
+                .iter()
+                .any(|pattern| is_subset_match(pattern, subject));
+        allowed
+            && !self
+                .deny
+                .iter()
+                .any(|pattern| is_subject_overlap(pattern, subject))
+    }
+}
+
+// 回复的权限, 收到的消息带有reply的话, 可以向这个reply发布max次, expires之后失效
+#[derive(Debug, Clone, Copy)]
+pub(super) struct ResponsePermission {
+    max: usize,
+    expires: Duration,
+}
+
+impl ResponsePermission {
+    // max为0或者expires没有设置的时候使用默认值
+    pub(super) fn new(max: usize, expires: Option<Duration>) -> Self {
+        Self {
+            max: if max == 0 { DEFAULT_RESPONSE_MAX } else { max },
+            expires: expires
+                .filter(|expires| !expires.is_zero())
+                .unwrap_or(DEFAULT_RESPONSE_EXPIRES),
+        }
+    }
+
+    // 不合法的expires在创建用户的时候已经检查过了, 这里当作没有设置
+    fn from_config(config: &ResponsePermissionConfig) -> Self {
+        let expires: Option<Duration> = config
+            .get_expires()
+            .and_then(|expires| Duration::try_from_secs_f64(expires).ok());
+        Self::new(config.get_max().unwrap_or_default(), expires)
+    }
+}
+
+// 用户的发布和订阅权限
+#[derive(Debug, Default)]
+pub(super) struct Permissions {
+    publish: Permission,
+    subscribe: Permission,
+    responses: Option<ResponsePermission>,
+}
+
+impl Permissions {
+    pub(super) fn new(publish: Permission, subscribe: Permission) -> Self {
+        Self {
+            publish,
+            subscribe,
+            responses: None,
+        }
+    }
+
+    pub(super) fn set_responses(mut self, responses: Option<ResponsePermission>) -> Self {
+        self.responses = responses;
+        self
+    }
+
+    pub(super) fn from_config(config: &PermissionsConfig) -> Self {
+        Self::new(
+            Permission::from_config(config.get_publish()),
+            Permission::from_config(config.get_subscribe()),
+        )
+        .set_responses(config.get_allow_responses().map(ResponsePermission::from_config))
+    }
+
+    pub(super) fn get_responses(&self) -> Option<ResponsePermission> {
+        self.responses
+    }
+
+    pub(super) fn find_invalid(&self) -> Option<&str> {
+        self.publish
+            .find_invalid()
+            .or_else(|| self.subscribe.find_invalid())
+    }
+
+    pub(super) fn can_publish(&self, subject: &str) -> bool {
+        self.publish.is_allowed(subject)
+    }
+
+    pub(super) fn can_subscribe(&self, subject: &str) -> bool {
+        self.subscribe.is_allowed(subject)
+    }
+}
+
+// 每个连接自己的检查结果缓存, 同一个subject不用每次都和所有的规则匹配
+// 用户的权限在连接的过程中不会改变, 所以不需要失效
+#[derive(Debug, Default)]
+pub(super) struct PermissionsCache {
+    publish: HashMap<String, bool>,
+    subscribe: HashMap<String, bool>,
+}
+
+impl PermissionsCache {
+    pub(super) fn can_publish(&mut self, permissions: &Permissions, subject: &str) -> bool {
+        Self::check(&mut self.publish, subject, |subject| {
+            permissions.can_publish(subject)
+        })
+    }
+
+    pub(super) fn can_subscribe(&mut self, permissions: &Permissions, subject: &str) -> bool {
+        Self::check(&mut self.subscribe, subject, |subject| {
+            permissions.can_subscribe(subject)
+        })
+    }
+
+    pub(super) fn clear(&mut self) {
+        self.publish.clear();
+        self.subscribe.clear();
+    }
+
+    fn check<F>(cache: &mut HashMap<String, bool>, subject: &str, condition: F) -> bool
+    where
+        F: FnOnce(&str) -> bool,
+    {
+        if let Some(allowed) = cache.get(subject) {
+            return *allowed;
+        }
+        let allowed: bool = condition(subject);
+        if cache.len() >= CACHE_MAX {
+            let removed: Vec<String> = cache.keys().take(CACHE_SWEEP).cloned().collect();
+            for key in removed {
+                cache.remove(&key);
+            }
+        }
+        cache.insert(subject.to_string(), allowed);
+        allowed
+    }
+}
+
+#[derive(Debug)]
+struct Reply {
+    remaining: usize,
+    deadline: Instant,
+}
+
+impl Reply {
+    fn is_expired(&self, now: Instant) -> bool {
+        self.deadline <= now
+    }
+}
+
+// 一个连接收到的消息里面的reply
+// 消息是在发布者的任务里面发送的, 所以要和订阅一起共享, 用锁保护
+// 每个连接只有一个, 重新CONNECT的时候只换权限, 原来的订阅记录的reply还能用
+#[derive(Debug, Default)]
+pub(super) struct Responses {
+    permission: Mutex<Option<ResponsePermission>>,
+    replies: Mutex<HashMap<String, Reply>>,
+}
+
+impl Responses {
+    // 新的用户没有回复权限的话, 原来记录的reply也不能再用了
+    pub(super) fn set_permission(&self, permission: Option<ResponsePermission>) {
+        *self.permission.lock().unwrap_or_else(PoisonError::into_inner) = permission;
+        if permission.is_none() {
+            self.lock_replies().clear();
+        }
+    }
+
+    fn get_permission(&self) -> Option<ResponsePermission> {
+        *self.permission.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // 发送带有reply的消息之前记录下来, 同一个reply再次收到的话重新计算
+    pub(super) fn add(&self, reply: &str) {
+        let permission: ResponsePermission = match self.get_permission() {
+            Some(permission) => permission,
+            None => return,
+        };
+        let now: Instant = Instant::now();
+        let mut replies = self.lock_replies();
+        if replies.len() >= REPLIES_MAX && !replies.contains_key(reply) {
+            replies.retain(|_, reply| !reply.is_expired(now));
+            if replies.len() >= REPLIES_MAX {
+                let removed: Vec<String> = replies.keys().take(REPLIES_SWEEP).cloned().collect();
+                for key in removed {
+                    replies.remove(&key);
+                }
+            }
+        }
+        replies.insert(
+            reply.to_string(),
+            Reply {
+                remaining: permission.max,
+                deadline: now + permission.expires,
+            },
+        );
+    }
+
+    // 向reply发布的时候用掉一次, 用完或者过期之后就删掉
+    pub(super) fn consume(&self, subject: &str) -> bool {
+        let mut replies = self.lock_replies();
+        let reply: &mut Reply = match replies.get_mut(subject) {
+            Some(reply) => reply,
+            None => return false,
+        };
+        if reply.is_expired(Instant::now()) {
+            replies.remove(subject);
+            return false;
+        }
+        reply.remaining -= 1;
+        if reply.remaining == 0 {
+            replies.remove(subject);
+        }
+        true
+    }
+
+    fn lock_replies(&self) -> MutexGuard<'_, HashMap<String, Reply>> {
+        self.replies.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+fn test_permission(allow: &[&str], deny: &[&str]) -> Permission {
+    Permission::new(
+        allow.iter().map(|subject| subject.to_string()).collect(),
+        deny.iter().map(|subject| subject.to_string()).collect(),
+    )
+}
+
+#[test]
+fn permissions_allow_and_deny() {
+    let permissions: Permissions = Permissions::new(
+        test_permission(&["orders.>", "_INBOX.*"], &["orders.internal.>"]),
+        test_permission(&[], &["secret.*"]),
+    );
+
+    assert!(permissions.can_publish("orders.created"));
+    assert!(permissions.can_publish("_INBOX.abc"));
+    assert!(!permissions.can_publish("orders.internal.audit"));
+    assert!(!permissions.can_publish("payments.created"));
+    assert!(!permissions.can_publish("_INBOX.abc.def"));
+
+    // 没有配置allow的话全部允许
+    assert!(permissions.can_subscribe("orders.created"));
+    assert!(permissions.can_subscribe("foo.>"));
+    assert!(!permissions.can_subscribe("secret.key"));
+    assert!(!permissions.can_subscribe("secret.*"));
+    // 通配符的订阅能收到禁止的subject的话也不允许
+    assert!(!permissions.can_subscribe(">"));
+    assert!(!permissions.can_subscribe("*.key"));
+    assert!(permissions.can_subscribe("*.key.id"));
+
+    // 带通配符的订阅不能超出allow的范围
+    let permission: Permission = test_permission(&["orders.*"], &[]);
+    assert!(permission.is_allowed("orders.*"));
+    assert!(!permission.is_allowed("orders.>"));
+    assert!(!permission.is_allowed(">"));
+}
+
+#[test]
+fn permissions_from_config() {
+    let config: PermissionsConfig = toml::from_str(
+        "publish = { allow = [\"orders.>\"] }\nsubscribe = { deny = [\"orders.internal.*\"] }",
+    )
+    .unwrap();
+    let permissions: Permissions = Permissions::from_config(&config);
+    assert!(permissions.find_invalid().is_none());
+    assert!(permissions.can_publish("orders.created"));
+    assert!(!permissions.can_publish("payments.created"));
+    assert!(permissions.can_subscribe("payments.created"));
+    assert!(!permissions.can_subscribe("orders.internal.audit"));
+
+    let config: PermissionsConfig = toml::from_str("subscribe = { allow = [\"orders..x\"] }").unwrap();
+    assert_eq!(Permissions::from_config(&config).find_invalid(), Some("orders..x"));
+}
+
+#[test]
+fn permissions_cache() {
+    let permissions: Permissions = Permissions::new(
+        test_permission(&["orders.>"], &[]),
+        test_permission(&[], &["secret.>"]),
+    );
+    let mut cache: PermissionsCache = PermissionsCache::default();
+
+    assert!(cache.can_publish(&permissions, "orders.created"));
+    assert!(!cache.can_publish(&permissions, "payments.created"));
+    assert_eq!(cache.publish.get("orders.created"), Some(&true));
+    assert_eq!(cache.publish.get("payments.created"), Some(&false));
+    assert!(!cache.can_subscribe(&permissions, "secret.key"));
+    assert_eq!(cache.subscribe.get("secret.key"), Some(&false));
+
+    // 缓存的数量有上限
+    for item in 0..CACHE_MAX * 2 {
+        assert!(cache.can_publish(&permissions, &format!("orders.{}", item)));
+    }
+    assert!(cache.publish.len() <= CACHE_MAX);
+
+    cache.clear();
+    assert!(cache.publish.is_empty() && cache.subscribe.is_empty());
+}
+
+#[cfg(test)]
+fn test_responses(max: usize, expires: Option<Duration>) -> Responses {
+    let responses: Responses = Responses::default();
+    responses.set_permission(Some(ResponsePermission::new(max, expires)));
+    responses
+}
+
+#[test]
+fn permissions_responses() {
+    let responses: Responses = test_responses(2, None);
+    assert!(!responses.consume("_INBOX.a"));
+
+    responses.add("_INBOX.a");
+    assert!(responses.consume("_INBOX.a"));
+    assert!(responses.consume("_INBOX.a"));
+    assert!(!responses.consume("_INBOX.a"));
+    assert!(responses.lock_replies().is_empty());
+
+    // 过期之后不能再回复
+    let responses: Responses = test_responses(0, Some(Duration::from_millis(10)));
+    responses.add("_INBOX.b");
+    responses.add("_INBOX.c");
+    assert!(responses.consume("_INBOX.b"));
+    assert!(!responses.consume("_INBOX.b"));
+    std::thread::sleep(Duration::from_millis(20));
+    assert!(!responses.consume("_INBOX.c"));
+
+    // 记录的数量有上限
+    let responses: Responses = test_responses(1, None);
+    for item in 0..REPLIES_MAX * 2 {
+        responses.add(&format!("_INBOX.{}", item));
+    }
+    assert!(responses.lock_replies().len() <= REPLIES_MAX);
+
+    let config: PermissionsConfig =
+        toml::from_str("allow_responses = { max = 3, expires = 1.5 }").unwrap();
+    let permission: ResponsePermission = Permissions::from_config(&config).get_responses().unwrap();
+    assert_eq!(permission.max, 3);
+    assert_eq!(permission.expires, Duration::from_millis(1500));
+    // 没有设置的话和nats一样2分钟过期
+    let config: PermissionsConfig = toml::from_str("allow_responses = {}").unwrap();
+    let permission: ResponsePermission = Permissions::from_config(&config).get_responses().unwrap();
+    assert_eq!(permission.max, DEFAULT_RESPONSE_MAX);
+    assert_eq!(permission.expires, DEFAULT_RESPONSE_EXPIRES);
+    let config: PermissionsConfig = toml::from_str("allow_responses = { expires = inf }").unwrap();
+    let permission: ResponsePermission = Permissions::from_config(&config).get_responses().unwrap();
+    assert_eq!(permission.expires, DEFAULT_RESPONSE_EXPIRES);
+}
+
+#[test]
+fn permissions_responses_set_permission() {
+    let responses: Responses = Responses::default();
+    responses.add("_INBOX.a");
+    assert!(!responses.consume("_INBOX.a"));
+
+    // 换了权限之后原来记录的reply还可以用
+    responses.set_permission(Some(ResponsePermission::new(1, None)));
+    responses.add("_INBOX.a");
+    responses.add("_INBOX.b");
+    responses.set_permission(Some(ResponsePermission::new(2, None)));
+    assert!(responses.consume("_INBOX.a"));
+
+    // 没有回复权限之后就都不能用了
+    responses.set_permission(None);
+    assert!(!responses.consume("_INBOX.b"));
+    responses.add("_INBOX.b");
+    assert!(!responses.consume("_INBOX.b"));
+}
@@ -1,25 +1,41 @@
+use super::account::{Accounts, Error as AccountError};
+use super::auth::{Auth, Error as AuthError};
+use super::jwt::{Error as JwtError, Operator};
 use super::service::Service;
-use super::sub_list::SubList;
-use crate::config::{Config, ServerConfig};
+use super::tls::{Error as TlsError, Tls};
+use crate::config::ServerConfig;
 use crate::global_static::CONFIG;
-use log::{error, info};
+use log::error;
 use std::io::Result as IoResult;
 use std::net::{AddrParseError, SocketAddr};
 use std::sync::Arc;
 use thiserror::Error;
-use tokio::io::{split, ReadHalf, WriteHalf};
-use tokio::net::{TcpListener, TcpStream};
+use tokio::net::TcpListener;
 use tokio::spawn;
-use tokio::sync::Mutex;
 
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("addr parse `{0}`")]
     AddrParse(#[from] AddrParseError),
+
+    #[error("tls `{0}`")]
+    Tls(#[from] TlsError),
+
+    #[error("auth `{0}`")]
+    Auth(#[from] AuthError),
+
+    #[error("jwt `{0}`")]
+    Jwt(#[from] JwtError),
+
+    #[error("account `{0}`")]
+    Account(#[from] AccountError),
 }
 
 pub struct Server {
     add: SocketAddr,
+    tls: Option<Tls>,
+    auth: Arc<Auth>,
+    accounts: Arc<Accounts>,
 }
 
 impl Server {
@@ -28,27 +44,42 @@ impl Server {
         let addr: SocketAddr =
             format!("{}:{}", server_config.get_ip(), server_config.get_port()).parse()?;
 
-        Ok(Self { add: addr })
+        // 证书有问题的话启动的时候就报错, 不用等到客户端连接
+        let tls: Option<Tls> = server_config.get_tls().map(Tls::new).transpose()?;
+
+        let mut auth: Auth = Auth::new(server_config.get_authorization())?;
+        if let Some(operator) = server_config.get_operator() {
+            auth = auth.set_operator(Operator::new(operator)?);
+        }
+        for (name, account) in server_config.get_accounts().into_iter().flatten() {
+            auth = auth.add_account(name, account)?;
+        }
+        let auth: Arc<Auth> = Arc::new(auth);
+        let accounts: Arc<Accounts> = Arc::new(Accounts::new(server_config.get_accounts())?);
+
+        Ok(Self {
+            add: addr,
+            tls,
+            auth,
+            accounts,
+        })
     }
 
     pub async fn run(self) -> IoResult<()> {
-        let mut listener = TcpListener::bind(self.add).await?;
+        let listener = TcpListener::bind(self.add).await?;
         let mut client_id: usize = 0;
-        let sub_list = Arc::new(Mutex::new(SubList::new()));
 
         loop {
             match listener.accept().await {
                 Ok((socket, addr)) => {
-                    let (read_stream, write_stream): (ReadHalf<TcpStream>, WriteHalf<TcpStream>) =
-                        split(socket);
-
                     let service: Service = Service::new(
-                        read_stream,
-                        write_stream,
+                        socket,
                         client_id,
                         self.add,
                         addr,
-                        sub_list.clone(),
+                        self.accounts.clone(),
+                        self.tls.clone(),
+                        self.auth.clone(),
                     );
                     client_id += 1;
 
@@ -1,71 +1,114 @@
-use super::decode::{Decode, Error, Message};
-use super::encode::{Info, Msg, Ping, Pong, ResponseOk};
+use super::account::{Account, Accounts, GLOBAL_ACCOUNT};
+use super::auth::{Auth, User};
+use super::decode::{Connect, Decode, Error, Message};
+use super::encode::{Info, Msg, Ping, Pong, ResponseErr, ResponseOk};
+use super::permissions::{PermissionsCache, Responses};
 use super::read_stream::ReadStream;
-use super::sub_list::SubList;
-use super::write_stream::WriteStream;
+use super::sub_list::{is_valid_literal_subject, SubList};
+use super::sub_struct::Subscription;
+use super::tls::{Socket, Tls};
+use super::write_stream::{Outbound, WriteStream};
 use crate::config::Config;
 use crate::config::ServerConfig;
 use crate::global_static::CONFIG;
+use bytes::Bytes;
 use log::{debug, error};
-use std::io::Result as IoResult;
+use std::collections::HashMap;
+use std::io::{Error as IoError, ErrorKind, Result as IoResult};
 use std::net::SocketAddr;
-use std::sync::Arc;
+use std::sync::{Arc, PoisonError, RwLock};
 use std::task::Poll;
-use std::collections::BTreeSet;
-use std::io::ErrorKind;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 // use std::time::{Duration, Instant};
-use tokio::io::{ReadHalf, WriteHalf, BufWriter};
+use tokio::io::{split, AsyncWriteExt, BufWriter};
 use tokio::net::TcpStream;
 use tokio::select;
-use tokio::sync::Mutex;
-use tokio::time::interval;
+use tokio::time::{interval_at, sleep, Instant};
+use uuid::Uuid;
 
-const BUFF_SIZE: usize = 2048;
-
-type ArcWriteStream = Arc<Mutex<WriteStream>>;
-type ArcSubList = Arc<Mutex<SubList<(ArcWriteStream, String, Option<u32>)>>>;
+// 订阅列表只有在SUB和UNSUB的时候才需要修改, 发布的时候只需要读锁
+// 锁都不会跨过await, 所以直接用标准库的读写锁
+pub(super) type ArcSubList = Arc<RwLock<SubList<Arc<Subscription>>>>;
 
 #[derive(Debug)]
 pub(super) struct Service {
-    read_stream: ReadStream,
-    write_stream: ArcWriteStream,
+    // run的时候握手之后才拆分成读写两部分
+    socket: Option<TcpStream>,
+    tls: Option<Tls>,
+    auth: Arc<Auth>,
+    // 客户端证书里面的身份, verify_and_map的时候用来对应用户
+    peer_identities: Vec<String>,
+    // 需要认证的话, CONNECT认证通过之前不能执行其他的操作
+    authorized: bool,
+    user: Option<Arc<User>>,
+    // 用户权限的检查结果
+    permissions_cache: PermissionsCache,
+    // 用户有回复权限的话, 记录收到的消息里面的reply
+    responses: Arc<Responses>,
+    // 发送给这个连接的数据都先放到这里, 由写入任务负责写到socket
+    outbound: Arc<Outbound>,
     decode: Decode,
     config: &'static Config,
     client_id: usize,
     local_addr: SocketAddr,
     remote_addr: SocketAddr,
-    sub_list: ArcSubList,
-    verbose: bool,
+    accounts: Arc<Accounts>,
+    // 连接所属的账户, 订阅和发布都只在这个账户的订阅列表里面
+    // 认证之前是全局账户, CONNECT的时候换成用户所属的账户
+    account: Arc<Account>,
+    // 当前连接的订阅, 用sid作为key
+    subscriptions: HashMap<String, Arc<Subscription>>,
+    // 客户端CONNECT的时候发送的选项, 没有发送CONNECT之前都是默认值
+    connect: Connect,
+    // 每个连接都不一样的随机数, 在INFO里面发送给客户端
+    nonce: String,
+    // 服务端主动ping客户端, 超过最大数量的ping没有回复的话就断开连接
+    ping_interval: Duration,
+    max_pings_outstanding: usize,
+    pings_outstanding: usize,
 }
 
 impl Service {
     pub(super) fn new(
-        read_stream: ReadHalf<TcpStream>,
-        write_stream: WriteHalf<TcpStream>,
+        socket: TcpStream,
         client_id: usize,
         local_addr: SocketAddr,
         remote_addr: SocketAddr,
-        sub_list: ArcSubList,
+        accounts: Arc<Accounts>,
+        tls: Option<Tls>,
+        auth: Arc<Auth>,
     ) -> Self {
-        let read_stream: ReadStream = ReadStream::new(read_stream);
-        let write_stream: ArcWriteStream = Arc::new(Mutex::new(WriteStream::new(BufWriter::new(write_stream))));
-
-        let decode: Decode = {
-            let server: &ServerConfig = CONFIG.get_server();
-            Decode::new(server.get_io_buffer_size())
-        };
+        let server: &ServerConfig = CONFIG.get_server();
+        let decode: Decode = Decode::new(server.get_io_buffer_size())
+            .set_max_payload(server.get_max_payload())
+            .set_max_control_line(server.get_max_control_line());
 
         Self {
-            read_stream,
-            write_stream,
+            socket: Some(socket),
+            tls,
+            auth,
+            peer_identities: Vec::new(),
+            authorized: false,
+            user: None,
+            permissions_cache: PermissionsCache::default(),
+            responses: Arc::new(Responses::default()),
+            outbound: Arc::new(Outbound::new(
+                server.get_max_pending(),
+                Duration::from_secs(server.get_write_timeout()),
+            )),
             decode,
             config: &CONFIG,
             client_id,
             local_addr,
             remote_addr,
-            sub_list,
-            verbose: false,
+            account: accounts.get_global().clone(),
+            accounts,
+            subscriptions: HashMap::new(),
+            connect: Connect::default(),
+            nonce: Uuid::new_v4().to_simple().to_string(),
+            ping_interval: Duration::from_secs(server.get_ping_interval()),
+            max_pings_outstanding: server.get_max_pings_outstanding(),
+            pings_outstanding: 0,
         }
     }
 
@@ -74,46 +117,206 @@ impl Service {
             "remote addr {} ==========> local addr {}",
             self.remote_addr, self.local_addr
         );
-        let mut buffer: Vec<u8> = {
-            let server: &ServerConfig = self.config.get_server();
-            vec![0; server.get_io_buffer_size()]
+        let socket: Socket = match self.accept().await {
+            Ok(socket) => socket,
+            Err(e) => {
+                debug!("remote addr {} accept error {:?}", self.remote_addr, e);
+                return;
+            }
         };
+        let (read_stream, write_stream) = split(socket);
+        let writer = tokio::spawn(
+            self.outbound
+                .clone()
+                .run(WriteStream::new(BufWriter::new(write_stream))),
+        );
 
-        {
-            let server: &ServerConfig = self.config.get_server();
-            let info: Info = Info::new()
-                .set_server_id(server.get_server_id().clone())
-                .set_server_name(server.get_server_name().clone())
-                .set_version(server.get_version().clone())
-                .set_host(server.get_ip().clone())
-                .set_port(server.get_port())
-                .set_auth_required(server.get_auth_required())
-                .set_ssl_required(server.get_ssl_required())
-                .set_max_payload(server.get_max_payload())
-                .set_proto(server.get_proto())
-                .set_client_id(self.client_id)
-                .set_client_ip(self.remote_addr.ip());
-
-            match info.format() {
-                Ok(result) => {
-                    debug!("local addr {} send info", self.local_addr);
-
-                    if let Err(e) = self.write_stream.lock().await.write(result.as_bytes()).await {
-                        error!("{:?}", e);
-                        return;
-                    }
-                }
-                Err(e) => {
-                    error!("{:?}", e);
-                    return;
-                }
+        self.serve(ReadStream::new(read_stream)).await;
+        self.close();
+
+        // 等待写入任务把剩下的数据写完
+        if let Err(e) = writer.await {
+            error!("{:?}", e);
+        }
+        debug!(
+            "remote addr {} user {:?} closed",
+            self.remote_addr,
+            self.user.as_ref().map(|user| user.get_name())
+        );
+    }
+
+    // 发送INFO, 配置了TLS的话还要完成握手
+    // 默认是和nats一样先用明文发送带有tls_required的INFO, 客户端收到之后再开始握手
+    // tls_first的话连接之后马上握手, INFO在握手之后发送
+    async fn accept(&mut self) -> IoResult<Socket> {
+        let mut socket: TcpStream = match self.socket.take() {
+            Some(socket) => socket,
+            None => return Err(IoError::from(ErrorKind::NotConnected)),
+        };
+        let info: String = self.info().format()?;
+        debug!("local addr {} send info", self.local_addr);
+
+        match &self.tls {
+            None => {
+                self.outbound.write(info.as_bytes())?;
+                Ok(Socket::Tcp(socket))
+            }
+            Some(tls) if tls.is_tls_first() => {
+                let socket: Socket = tls.accept(socket).await?;
+                self.peer_identities = socket.get_peer_identities();
+                self.outbound.write(info.as_bytes())?;
+                Ok(socket)
+            }
+            Some(tls) => {
+                socket.write_all(info.as_bytes()).await?;
+                let socket: Socket = tls.accept(socket).await?;
+                self.peer_identities = socket.get_peer_identities();
+                Ok(socket)
             }
         }
+    }
+
+    fn is_verify_and_map(&self) -> bool {
+        self.tls.as_ref().is_some_and(Tls::is_verify_and_map)
+    }
 
-        let mut inter = interval(Duration::from_micros(500));
+    fn is_auth_required(&self) -> bool {
+        self.is_verify_and_map() || self.auth.is_required()
+    }
+
+    // CONNECT的时候检查认证, 失败的话要断开连接
+    async fn authenticate(&mut self) -> Result<(), Error> {
+        if !self.is_auth_required() {
+            self.authorized = true;
+            return Ok(());
+        }
+        // verify_and_map的话只看证书, 不需要token或者密码
+        let user: Option<Arc<User>> = if self.is_verify_and_map() {
+            Some(
+                self.auth
+                    .map_identities(&self.peer_identities)
+                    .ok_or(Error::AuthorizationViolation)?,
+            )
+        } else {
+            self.auth.authenticate(&self.connect, &self.nonce).await?
+        };
+        if let Some(user) = &user {
+            debug!("remote addr {} user {}", self.remote_addr, user.get_name());
+        }
+        // 已经有的订阅和新的订阅共用同一个记录, 只换权限
+        self.responses.set_permission(
+            user.as_ref()
+                .and_then(|user| user.get_permissions())
+                .and_then(|permissions| permissions.get_responses()),
+        );
+        // 换了账户的话, 原来账户里面的订阅都要取消
+        let account: Arc<Account> = self.accounts.get_or_create(
+            user.as_ref()
+                .map_or(GLOBAL_ACCOUNT, |user| user.get_account()),
+        );
+        if !Arc::ptr_eq(&account, &self.account) {
+            self.unsubscribe_all();
+            debug!("remote addr {} account {}", self.remote_addr, account.get_name());
+            self.account = account;
+        }
+        self.user = user;
+        self.permissions_cache.clear();
+        self.authorized = true;
+        Ok(())
+    }
+
+    fn get_expires(&self) -> Option<SystemTime> {
+        self.user.as_ref().and_then(|user| user.get_expires())
+    }
+
+    // 用户的最大payload和发布权限, 超过最大payload的话要断开连接
+    // 解析出来的消息还借用着decode, 所以只传需要的字段
+    fn check_publish(
+        user: &Option<Arc<User>>,
+        permissions_cache: &mut PermissionsCache,
+        responses: &Responses,
+        subject: &str,
+        size: usize,
+    ) -> Result<(), Error> {
+        let user: &User = match user {
+            Some(user) => user,
+            None => return Ok(()),
+        };
+        if user.get_max_payload().is_some_and(|max_payload| size > max_payload) {
+            return Err(Error::MaxPayload);
+        }
+        // 没有发布权限的话, 再看是不是在回复收到的消息
+        let denied: bool = user
+            .get_permissions()
+            .is_some_and(|permissions| !permissions_cache.can_publish(permissions, subject));
+        if denied && !responses.consume(subject) {
+            return Err(Error::PublishViolation(subject.to_string()));
+        }
+        Ok(())
+    }
+
+    // 用户的订阅权限和最大订阅数量
+    fn check_subscribe(
+        user: &Option<Arc<User>>,
+        permissions_cache: &mut PermissionsCache,
+        subscriptions: usize,
+        subject: &str,
+    ) -> Result<(), Error> {
+        let user: &User = match user {
+            Some(user) => user,
+            None => return Ok(()),
+        };
+        if user
+            .get_permissions()
+            .is_some_and(|permissions| !permissions_cache.can_subscribe(permissions, subject))
+        {
+            return Err(Error::SubscriptionViolation(subject.to_string()));
+        }
+        if user
+            .get_max_subs()
+            .is_some_and(|max_subs| subscriptions >= max_subs)
+        {
+            return Err(Error::MaxSubscriptions);
+        }
+        Ok(())
+    }
+
+    fn info(&self) -> Info {
+        let server: &ServerConfig = self.config.get_server();
+        Info::new()
+            .set_server_id(server.get_server_id().clone())
+            .set_server_name(server.get_server_name().clone())
+            .set_version(server.get_version().clone())
+            .set_host(server.get_ip().clone())
+            .set_port(server.get_port())
+            .set_auth_required(self.is_auth_required())
+            .set_ssl_required(self.tls.is_some())
+            .set_tls_required(self.tls.is_some())
+            .set_max_payload(server.get_max_payload())
+            .set_proto(server.get_proto())
+            .set_headers(true)
+            .set_nonce(self.nonce.clone())
+            .set_client_id(self.client_id)
+            .set_client_ip(self.remote_addr.ip())
+    }
+
+    async fn serve(&mut self, mut read_stream: ReadStream) {
+        self.authorized = !self.is_auth_required();
+        let mut buffer: Vec<u8> = {
+            let server: &ServerConfig = self.config.get_server();
+            vec![0; server.get_io_buffer_size()]
+        };
+
+        // ping_interval配置成0表示不ping客户端, 定时器的周期不能是0, 这时候定时器只是占位, 不会被用到
+        let ping_period: Duration = self.ping_interval.max(Duration::from_millis(1));
+        let mut ping_inter = interval_at(Instant::now() + ping_period, ping_period);
+        // 需要认证的话, 超时之前没有通过认证就断开连接
+        // 认证之后如果用户的JWT会过期, 就换成过期的时间
+        let auth_deadline = sleep(self.auth.get_timeout());
+        tokio::pin!(auth_deadline);
         'main: loop {
             select! {
-                result = self.read_stream.read(&mut buffer) => {
+                result = read_stream.read(&mut buffer) => {
                     match result {
                         Ok(size) => {
                             // 如果读取字节流的时候获取到0长度字节流,
@@ -138,127 +341,182 @@ impl Service {
                                             match poll {
                                                 Poll::Ready(message) => {
                                                     match message {
+                                                        // 需要认证的话, 第一个协议必须是CONNECT
+                                                        _ if !self.authorized && !matches!(message, Message::Connect(_)) => {
+                                                            if let Err(e) = self.send_err(&Error::AuthorizationViolation) {
+                                                                error!("{:?}", e);
+                                                            }
+                                                            break 'main;
+                                                        }
                                                         // 由于这里的message的参数都是借用的, 所以尽量在原地使用
-                                                        Message::Connect(conn_info) => {
+                                                        Message::Connect(connect) => {
                                                             debug!(
-                                                                "remote addr {} send connect",
-                                                                self.remote_addr
+                                                                "remote addr {} send connect, name {:?} lang {:?} version {:?}",
+                                                                self.remote_addr,
+                                                                connect.get_name(),
+                                                                connect.get_lang(),
+                                                                connect.get_version()
                                                             );
 
-                                                            if let Some(ssl_require) = conn_info
-                                                                .get("ssl_require")
-                                                                .and_then(|v| v.as_bool())
-                                                            {
-                                                                self.set_ssl(ssl_require).await;
+                                                            self.connect = connect;
+                                                            if let Err(e) = self.authenticate().await {
+                                                                if let Err(e) = self.send_err(&e) {
+                                                                    error!("{:?}", e);
+                                                                }
+                                                                break 'main;
                                                             }
-
-                                                            if let Some(verbose) = conn_info
-                                                                .get("verbose")
-                                                                .and_then(|v| v.as_bool())
-                                                            {
-                                                                self.set_verbose(verbose).await;
+                                                            if let Some(expires) = self.get_expires() {
+                                                                let remaining: Duration = expires
+                                                                    .duration_since(SystemTime::now())
+                                                                    .unwrap_or_default();
+                                                                auth_deadline.as_mut().reset(Instant::now() + remaining);
                                                             }
-
-                                                            if let Err(e) = self.send_ok().await {
+                                                            if let Err(e) = self.send_ok() {
                                                                 error!("{:?}", e);
                                                             }
                                                         }
                                                         Message::Sub(subject, group, sid) => {
-                                                            debug!("remote addr {} send sub, subject {} sid {}", self.remote_addr, subject, sid);
+                                                            debug!("remote addr {} send sub, subject {} group {:?} sid {}", self.remote_addr, subject, group, sid);
 
-                                                            let mut sub_list = self.sub_list.lock().await;
-                                                            (*sub_list).subscribe(
-                                                                subject.to_string(),
-                                                                (
-                                                                    self.write_stream.clone(),
+                                                            // 自动取消订阅发送够了的订阅已经从SubList里面删除了, 这里也要去掉,
+                                                            // 不然sid不能重新使用, 也会被算进最大订阅数量里面
+                                                            self.subscriptions.retain(|_, subscription| !subscription.is_exhausted());
+                                                            if self.subscriptions.contains_key(sid) {
+                                                                debug!("remote addr {} sid {} already exists", self.remote_addr, sid);
+                                                                if let Err(e) = self.send_ok() {
+                                                                    error!("{:?}", e);
+                                                                }
+                                                            } else if let Err(e) = Self::check_subscribe(&self.user, &mut self.permissions_cache, self.subscriptions.len(), subject) {
+                                                                debug!("remote addr {} {}", self.remote_addr, e);
+                                                                if let Err(e) = self.send_err(&e) {
+                                                                    error!("{:?}", e);
+                                                                }
+                                                            } else {
+                                                                let subscription: Arc<Subscription> = Arc::new(Subscription::new(
+                                                                    self.outbound.clone(),
+                                                                    self.client_id,
                                                                     sid.to_string(),
-                                                                    None,
-                                                                ),
-                                                            );
-                                                        }
-                                                        Message::Pub(subject, reply_to, content) => {
-                                                            debug!(
-                                                                "remote addr {} pub subject {} content {}",
-                                                                self.remote_addr, subject, content
-                                                            );
-                                                            {
-                                                                // let start = Instant::now();
-                                                                let mut sub_list =
-                                                                    self.sub_list.lock().await;
-                                                                // debug!("sub {:?}", Instant::now().checked_duration_since(start));
-
-                                                                if let Some(list) = (*sub_list)
-                                                                    .get_subscribe_item(subject.to_string())
-                                                                {
-                                                                    let mut remove_index: BTreeSet<usize> =
-                                                                    BTreeSet::new();
-                                                                    let msg = Msg::new(
-                                                                        subject, reply_to, content,
-                                                                    );
-
-                                                                    for (
-                                                                        index,
-                                                                        (write_stream, sid, max_message),
-                                                                    ) in list.iter_mut().enumerate()
-                                                                    {
-                                                                        // 由于发布的协议除了sid是不同以外, 其他的都是一样
-                                                                        // 所以要预先拼好sid前后的值, 重复利用
-                                                                        if let Err(e) = write_stream.lock().await.write(msg.get_front_chunk()).await {
-                                                                            if let ErrorKind::BrokenPipe = e.kind() {
-                                                                                remove_index.insert(index);
-                                                                            }
-                                                                            error!("{:?}", e);
-                                                                        }
-                                                                        if let Err(e) = write_stream.lock().await.write(sid.as_bytes()).await {
-                                                                            if let ErrorKind::BrokenPipe = e.kind() {
-                                                                                remove_index.insert(index);
-                                                                            }
+                                                                    subject.to_string(),
+                                                                    group.map(|group| group.to_string()),
+                                                                    self.connect.supports_headers(),
+                                                                ).set_responses(Some(self.responses.clone())));
+                                                                let result = self.account.get_sub_list().write().unwrap_or_else(PoisonError::into_inner).subscribe(
+                                                                    subject.to_string(),
+                                                                    group.map(|group| group.to_string()),
+                                                                    subscription.clone(),
+                                                                );
+                                                                match result {
+                                                                    Ok(()) => {
+                                                                        self.subscriptions.insert(sid.to_string(), subscription);
+                                                                        if let Err(e) = self.send_ok() {
                                                                             error!("{:?}", e);
                                                                         }
-                                                                        if let Err(e) = write_stream.lock().await.write(msg.get_after_chunk()).await {
-                                                                            if let ErrorKind::BrokenPipe = e.kind() {
-                                                                                remove_index.insert(index);
-                                                                            }
+                                                                    }
+                                                                    Err(e) => {
+                                                                        error!("{:?}", e);
+                                                                        if let Err(e) = self.send_err(&Error::InvalidSubject) {
                                                                             error!("{:?}", e);
                                                                         }
-                                                                        // debug!("send msg {:?}", Instant::now().checked_duration_since(start_write));
-
-                                                                        // 倒序标记删除的下标
-                                                                        if let Some(max) = max_message {
-                                                                            if *max > 0 {
-                                                                                *max -= 1;
-                                                                            } else {
-                                                                                remove_index
-                                                                                    .insert(index);
-                                                                            }
-                                                                        }
                                                                     }
-
-                                                                    // let start_remove = Instant::now();
-                                                                    // 通过倒序删除数组相应位置
-                                                                    for index in remove_index.into_iter().rev() {
-                                                                        list.remove(index);
+                                                                }
+                                                            }
+                                                        }
+                                                        Message::Pub(subject, reply_to, content) => {
+                                                            debug!(
+                                                                "remote addr {} pub subject {} content length {}",
+                                                                self.remote_addr, subject, content.len()
+                                                            );
+                                                            // pedantic模式下, 发布的subject不能带有通配符
+                                                            let valid_subject: bool = !self.connect.is_pedantic()
+                                                                || is_valid_literal_subject(subject);
+                                                            let result = match Self::check_publish(&self.user, &mut self.permissions_cache, &self.responses, subject, content.len()) {
+                                                                Ok(()) if valid_subject => {
+                                                                    // CONNECT的时候设置了echo:false的话, 发布的消息不会发送给自己的订阅
+                                                                    let no_echo: Option<usize> = (!self.connect.is_echo()).then_some(self.client_id);
+                                                                    Self::publish(&self.account, subject, reply_to, None, content, no_echo);
+                                                                    self.send_ok()
+                                                                }
+                                                                Ok(()) => self.send_err(&Error::InvalidPublishSubject),
+                                                                Err(e) => {
+                                                                    debug!("remote addr {} {}", self.remote_addr, e);
+                                                                    if let Err(err) = self.send_err(&e) {
+                                                                        error!("{:?}", err);
+                                                                    }
+                                                                    if e.is_fatal() {
+                                                                        break 'main;
                                                                     }
-                                                                    // debug!("remove {:?}", Instant::now().checked_duration_since(start_remove));
+                                                                    Ok(())
+                                                                }
+                                                            };
+                                                            if let Err(e) = result {
+                                                                error!("{:?}", e);
+                                                            }
+                                                        }
+                                                        Message::HPub(subject, reply_to, headers, content) => {
+                                                            debug!(
+                                                                "remote addr {} hpub subject {} headers length {} content length {}",
+                                                                self.remote_addr, subject, headers.len(), content.len()
+                                                            );
+                                                            // 和nats一样, CONNECT的时候没有声明支持header的话, HPUB是不认识的协议
+                                                            if !self.connect.supports_headers() {
+                                                                if let Err(e) = self.send_err(&Error::UnknownProtocol) {
+                                                                    error!("{:?}", e);
                                                                 }
+                                                                break 'main;
                                                             }
-                                                            if let Err(e) = self.send_ok().await {
+                                                            let valid_subject: bool = !self.connect.is_pedantic()
+                                                                || is_valid_literal_subject(subject);
+                                                            let result = match Self::check_publish(&self.user, &mut self.permissions_cache, &self.responses, subject, headers.len() + content.len()) {
+                                                                Ok(()) if valid_subject => {
+                                                                    // CONNECT的时候设置了echo:false的话, 发布的消息不会发送给自己的订阅
+                                                                    let no_echo: Option<usize> = (!self.connect.is_echo()).then_some(self.client_id);
+                                                                    Self::publish(&self.account, subject, reply_to, Some(headers), content, no_echo);
+                                                                    self.send_ok()
+                                                                }
+                                                                Ok(()) => self.send_err(&Error::InvalidPublishSubject),
+                                                                Err(e) => {
+                                                                    debug!("remote addr {} {}", self.remote_addr, e);
+                                                                    if let Err(err) = self.send_err(&e) {
+                                                                        error!("{:?}", err);
+                                                                    }
+                                                                    if e.is_fatal() {
+                                                                        break 'main;
+                                                                    }
+                                                                    Ok(())
+                                                                }
+                                                            };
+                                                            if let Err(e) = result {
                                                                 error!("{:?}", e);
                                                             }
                                                         }
                                                         Message::UnSub(sid, max_messages) => {
-                                                            let mut sublist = self.sub_list.lock().await;
-                                                            sublist.remove_subscription(|(_, ssid, _)| {
-                                                                ssid.eq(&sid)
-                                                            });
-                                                        }
-                                                        Message::Pong => {
-                                                            if let Err(e) = self.send_ping().await {
+                                                            debug!("remote addr {} send unsub, sid {} max messages {:?}", self.remote_addr, sid, max_messages);
+
+                                                            if let Some(subscription) = self.subscriptions.get(sid) {
+                                                                // 带了最大数量的话, 要等发送够了才取消订阅
+                                                                // 已经发送过的消息也算在里面, 已经够了的话就马上取消
+                                                                let remove: bool = match max_messages {
+                                                                    Some(max_messages) if max_messages > 0 => {
+                                                                        subscription.set_max_msgs(u64::from(max_messages))
+                                                                    }
+                                                                    _ => true,
+                                                                };
+
+                                                                if remove {
+                                                                    let mut sub_list = self.account.get_sub_list().write().unwrap_or_else(PoisonError::into_inner);
+                                                                    subscription.unsubscribe_from(&mut sub_list);
+                                                                    self.subscriptions.remove(sid);
+                                                                }
+                                                            }
+                                                            if let Err(e) = self.send_ok() {
                                                                 error!("{:?}", e);
                                                             }
                                                         }
+                                                        Message::Pong => {
+                                                            self.pings_outstanding = 0;
+                                                        }
                                                         Message::Ping => {
-                                                            if let Err(e) = self.send_pong().await {
+                                                            if let Err(e) = self.send_pong() {
                                                                 error!("{:?}", e);
                                                             }
                                                         }
@@ -272,6 +530,16 @@ impl Service {
                                         }
                                         Err(e) => {
                                             error!("decode error {:?}", e);
+
+                                            if let Err(e) = self.send_err(&e) {
+                                                error!("{:?}", e);
+                                            }
+                                            // 致命的错误直接断开连接,
+                                            // 否则丢掉出错的那一行, 继续解析后面的数据
+                                            if e.is_fatal() {
+                                                break 'main;
+                                            }
+                                            self.decode.reset();
                                         }
                                     }
                                 }
@@ -283,36 +551,1416 @@ impl Service {
                         }
                     }
                 }
-                _ = inter.tick() => {
-                    if let Err(e) = self.write_stream.lock().await.flush().await {
-                        error!("flush error {:?}", e);
+                // 写入失败或者是慢消费者, 写入任务会关闭outbound
+                _ = self.outbound.closed() => {
+                    debug!("remote addr {} outbound closed", self.remote_addr);
+                    break 'main;
+                }
+                _ = ping_inter.tick(), if !self.ping_interval.is_zero() => {
+                    if self.pings_outstanding + 1 > self.max_pings_outstanding {
+                        debug!("remote addr {} stale connection", self.remote_addr);
+                        if let Err(e) = self.send_err(&Error::StaleConnection) {
+                            error!("{:?}", e);
+                        }
+                        break 'main;
+                    }
+
+                    if let Err(e) = self.send_ping() {
+                        error!("{:?}", e);
                     }
+                    self.pings_outstanding += 1;
+                }
+                _ = &mut auth_deadline, if !self.authorized || self.get_expires().is_some() => {
+                    let e: Error = if self.authorized {
+                        Error::AuthenticationExpired
+                    } else {
+                        Error::AuthenticationTimeout
+                    };
+                    debug!("remote addr {} {}", self.remote_addr, e);
+                    if let Err(e) = self.send_err(&e) {
+                        error!("{:?}", e);
+                    }
+                    break 'main;
                 }
             }
         }
     }
 
-    async fn set_ssl(&mut self, ssl_required: bool) {
-        self.read_stream.set_ssl(ssl_required);
-        self.write_stream.lock().await.set_ssl(ssl_required);
+    // 把消息发送给自己账户的订阅, 再按照账户的导入导出发送到其他账户的订阅
+    fn publish(
+        account: &Arc<Account>,
+        subject: &str,
+        reply_to: Option<&str>,
+        headers: Option<Bytes>,
+        content: Bytes,
+        no_echo: Option<usize>,
+    ) {
+        for route in account.get_routes(subject, reply_to) {
+            Self::deliver(
+                route.get_sub_list(),
+                route.get_subject(),
+                route.get_reply_to(),
+                headers.clone(),
+                content.clone(),
+                no_echo,
+            );
+        }
+        Self::deliver(account.get_sub_list(), subject, reply_to, headers, content, no_echo);
     }
 
-    async fn set_verbose(&mut self, verbose: bool) {
-        self.write_stream.lock().await.set_verbose(verbose);
+    // 把消息发送给所有匹配的订阅
+    // 带header的消息只发送给支持header的连接, 其他的连接要去掉header再发送
+    // no_echo是发布者的client_id, 不会发送给这个连接的订阅
+    fn deliver(
+        sub_list: &ArcSubList,
+        subject: &str,
+        reply_to: Option<&str>,
+        headers: Option<Bytes>,
+        content: Bytes,
+        no_echo: Option<usize>,
+    ) {
+        // 只有匹配的时候持有读锁, 匹配结果是复制出来的, 发送的时候不需要再持有锁
+        let result = sub_list.read().unwrap_or_else(PoisonError::into_inner).match_subject(subject);
+        if !result.is_empty() {
+            let header_msg: Option<Msg> = headers
+                .map(|headers| Msg::with_headers(subject, reply_to, headers, content.clone()));
+            let msg = Msg::new(subject, reply_to, content);
+
+            let is_echo = |subscription: &Arc<Subscription>| {
+                no_echo != Some(subscription.get_client_id())
+            };
+
+            // 普通的订阅全部发送, 队列组的订阅每组只发送给一个
+            // UNSUB设置了最大数量的话, 发送够了就不再发送
+            // 队列组里面挑中的订阅刚好用完的话, 要换下一个, 不然整个组都收不到
+            let list = result
+                .get_subs()
+                .iter()
+                .filter(|subscription| is_echo(subscription) && subscription.take())
+                .chain(result.pick_queue_subs(|subscription| {
+                    is_echo(subscription) && subscription.take()
+                }));
+
+            let mut removed: Vec<&Arc<Subscription>> = Vec::new();
+            for subscription in list {
+                let msg: &Msg = match &header_msg {
+                    Some(header_msg) if subscription.supports_headers() => header_msg,
+                    _ => &msg,
+                };
+
+                // 由于发布的协议除了sid是不同以外, 其他的都是一样
+                // 所以要预先拼好sid前后的值, 重复利用
+                // 这里只是把payload的引用放进订阅者的发送缓冲区, 不会等待订阅者的socket
+                if let Some(reply_to) = reply_to {
+                    subscription.add_reply(reply_to);
+                }
+                let mut closed: bool = false;
+                if let Err(e) = subscription
+                    .get_outbound()
+                    .write_chunks(&msg.get_chunks(subscription.get_sid()))
+                {
+                    debug!("{:?}", e);
+                    closed = true;
+                }
+
+                if closed || subscription.is_exhausted() {
+                    removed.push(subscription);
+                }
+            }
+
+            // 匹配结果是复制出来的, 所以要回到订阅列表里面删除
+            if !removed.is_empty() {
+                let mut sub_list = sub_list.write().unwrap_or_else(PoisonError::into_inner);
+                for subscription in removed {
+                    subscription.unsubscribe_from(&mut sub_list);
+                }
+            }
+        }
+    }
+
+    // 连接断开之后, 要把这个连接的订阅全部删除
+    fn close(&mut self) {
+        self.unsubscribe_all();
+
+        self.outbound.close();
     }
 
-    async fn send_ok(&mut self) -> IoResult<()> {
-        if self.verbose {
-            self.write_stream.lock().await.write(ResponseOk::format()).await?;
+    fn unsubscribe_all(&mut self) {
+        let mut sub_list = self.account.get_sub_list().write().unwrap_or_else(PoisonError::into_inner);
+        for (_, subscription) in self.subscriptions.drain() {
+            subscription.unsubscribe_from(&mut sub_list);
+        }
+    }
+
+    fn send_ok(&self) -> IoResult<()> {
+        if self.connect.is_verbose() {
+            self.outbound.write(ResponseOk::format())?;
         }
         Ok(())
     }
 
-    async fn send_ping(&mut self) -> IoResult<()> {
-        self.write_stream.lock().await.write(Ping::format()).await
+    fn send_err(&self, error: &Error) -> IoResult<()> {
+        self.outbound.write(&ResponseErr::format(error))
+    }
+
+    fn send_ping(&self) -> IoResult<()> {
+        self.outbound.write(Ping::format())
+    }
+
+    fn send_pong(&self) -> IoResult<()> {
+        self.outbound.write(Pong::format())
+    }
+}
+
+// 测试用的服务, 每个测试都有自己的订阅列表, 互不影响
+#[cfg(test)]
+async fn test_server() -> (SocketAddr, ArcSubList) {
+    test_server_with(|_| {}).await
+}
+
+// 可以在启动每一个连接之前修改Service的配置
+#[cfg(test)]
+async fn test_server_with<F>(setup: F) -> (SocketAddr, ArcSubList)
+where
+    F: Fn(&mut Service) + Send + 'static,
+{
+    use tokio::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let local_addr: SocketAddr = listener.local_addr().unwrap();
+    let accounts: Arc<Accounts> = Arc::new(Accounts::new(None).unwrap());
+    let sub_list: ArcSubList = accounts.get_global().get_sub_list().clone();
+
+    tokio::spawn(async move {
+        let mut client_id: usize = 0;
+        while let Ok((socket, remote_addr)) = listener.accept().await {
+            let mut service: Service = Service::new(
+                socket,
+                client_id,
+                local_addr,
+                remote_addr,
+                accounts.clone(),
+                None,
+                Arc::new(Auth::new(None).unwrap()),
+            );
+            setup(&mut service);
+            client_id += 1;
+            tokio::spawn(service.run());
+        }
+    });
+
+    (local_addr, sub_list)
+}
+
+// 一直读取, 直到一段时间内都没有新的数据
+#[cfg(test)]
+async fn test_read<S>(stream: &mut S) -> Vec<u8>
+where
+    S: tokio::io::AsyncRead + Unpin,
+{
+    use tokio::io::AsyncReadExt;
+    use tokio::time::timeout;
+
+    let mut result: Vec<u8> = Vec::new();
+    let mut buff: Vec<u8> = vec![0; 4096];
+    while let Ok(Ok(size)) = timeout(Duration::from_millis(300), stream.read(&mut buff)).await {
+        if size == 0 {
+            break;
+        }
+        result.extend_from_slice(&buff[..size]);
+    }
+    result
+}
+
+#[cfg(test)]
+fn test_count(buff: &[u8], pattern: &[u8]) -> usize {
+    buff.windows(pattern.len())
+        .filter(|window| *window == pattern)
+        .count()
+}
+
+#[tokio::test]
+async fn service_unsub_max_messages() {
+    use tokio::io::AsyncWriteExt;
+
+    let (addr, _) = test_server().await;
+    let mut client = TcpStream::connect(addr).await.unwrap();
+
+    // 已经发送过的2条也算在5条里面
+    client
+        .write_all(b"SUB foo 1\r\nPUB foo 2\r\nhi\r\nPUB foo 2\r\nhi\r\nUNSUB 1 5\r\n")
+        .await
+        .unwrap();
+    for _ in 0..10 {
+        client.write_all(b"PUB foo 2\r\nhi\r\n").await.unwrap();
+    }
+
+    let result: Vec<u8> = test_read(&mut client).await;
+    assert_eq!(test_count(&result, b"MSG foo 1 2\r\nhi\r\n"), 5);
+}
+
+#[tokio::test]
+async fn service_unsub_max_messages_reached() {
+    use tokio::io::AsyncWriteExt;
+
+    let (addr, _) = test_server().await;
+    let mut client = TcpStream::connect(addr).await.unwrap();
+
+    // 已经发送了3条, 超过了最大数量, 要马上取消订阅
+    client
+        .write_all(b"SUB foo 1\r\nPUB foo 2\r\nhi\r\nPUB foo 2\r\nhi\r\nPUB foo 2\r\nhi\r\nUNSUB 1 2\r\n")
+        .await
+        .unwrap();
+    for _ in 0..3 {
+        client.write_all(b"PUB foo 2\r\nhi\r\n").await.unwrap();
+    }
+
+    let result: Vec<u8> = test_read(&mut client).await;
+    assert_eq!(test_count(&result, b"MSG foo 1 2\r\nhi\r\n"), 3);
+}
+
+#[tokio::test]
+async fn service_unsub_request_inbox() {
+    use tokio::io::AsyncWriteExt;
+
+    let (addr, _) = test_server().await;
+    let mut requester = TcpStream::connect(addr).await.unwrap();
+    let mut responder = TcpStream::connect(addr).await.unwrap();
+
+    // request/reply的客户端订阅inbox之后马上 UNSUB <sid> 1
+    requester
+        .write_all(b"SUB _INBOX.abc 7\r\nUNSUB 7 1\r\nPING\r\n")
+        .await
+        .unwrap();
+    test_read(&mut requester).await;
+
+    for _ in 0..3 {
+        responder
+            .write_all(b"PUB _INBOX.abc 4\r\npong\r\n")
+            .await
+            .unwrap();
+    }
+
+    let result: Vec<u8> = test_read(&mut requester).await;
+    assert_eq!(test_count(&result, b"MSG _INBOX.abc 7 4\r\npong\r\n"), 1);
+}
+
+#[tokio::test]
+async fn service_unsub_reuse_sid() {
+    use tokio::io::AsyncWriteExt;
+
+    let (addr, sub_list) = test_server().await;
+    let mut client = TcpStream::connect(addr).await.unwrap();
+
+    // 自动取消订阅之后, 同一个sid可以重新订阅
+    client
+        .write_all(b"SUB foo 1\r\nUNSUB 1 1\r\nPUB foo 2\r\nhi\r\nPING\r\n")
+        .await
+        .unwrap();
+    let result: Vec<u8> = test_read(&mut client).await;
+    assert_eq!(test_count(&result, b"MSG foo 1 2\r\nhi\r\n"), 1);
+
+    client
+        .write_all(b"SUB foo 1\r\nPUB foo 2\r\nhi\r\nPUB foo 2\r\nhi\r\nPING\r\n")
+        .await
+        .unwrap();
+    let result: Vec<u8> = test_read(&mut client).await;
+    assert_eq!(test_count(&result, b"MSG foo 1 2\r\nhi\r\n"), 2);
+    assert_eq!(sub_list.read().unwrap().match_subject("foo").get_subs().len(), 1);
+}
+
+#[tokio::test]
+async fn service_unsub_same_sid() {
+    use tokio::io::AsyncWriteExt;
+
+    let (addr, _) = test_server().await;
+    let mut first = TcpStream::connect(addr).await.unwrap();
+    let mut second = TcpStream::connect(addr).await.unwrap();
+
+    // 不同连接的sid是可以重复的, 取消订阅只能影响到自己
+    first.write_all(b"SUB foo 1\r\nPING\r\n").await.unwrap();
+    second.write_all(b"SUB foo 1\r\nPING\r\n").await.unwrap();
+    test_read(&mut first).await;
+    test_read(&mut second).await;
+
+    second.write_all(b"UNSUB 1\r\nPUB foo 2\r\nhi\r\n").await.unwrap();
+
+    let result: Vec<u8> = test_read(&mut first).await;
+    assert_eq!(test_count(&result, b"MSG foo 1 2\r\nhi\r\n"), 1);
+    let result: Vec<u8> = test_read(&mut second).await;
+    assert_eq!(test_count(&result, b"MSG foo 1 2\r\nhi\r\n"), 0);
+}
+
+#[tokio::test]
+async fn service_sub_list_cache_stats() {
+    use tokio::io::AsyncWriteExt;
+
+    let (addr, sub_list) = test_server().await;
+    let mut client = TcpStream::connect(addr).await.unwrap();
+
+    // 第一次发布的时候没有缓存, 后面的发布都是命中缓存
+    client
+        .write_all(b"SUB foo 1\r\nPUB foo 2\r\nhi\r\nPUB foo 2\r\nhi\r\nPUB foo 2\r\nhi\r\nPING\r\n")
+        .await
+        .unwrap();
+    let result: Vec<u8> = test_read(&mut client).await;
+    assert_eq!(test_count(&result, b"MSG foo 1 2\r\nhi\r\n"), 3);
+    let sub_list = sub_list.read().unwrap();
+    assert_eq!(sub_list.get_cache_misses(), 1);
+    assert_eq!(sub_list.get_cache_hits(), 2);
+}
+
+#[test]
+fn service_deliver_queue_exhausted_member() {
+    let sub_list: ArcSubList = Arc::new(RwLock::new(SubList::new()));
+    let new_subscription = |client_id: usize| {
+        Arc::new(Subscription::new(
+            Arc::new(Outbound::new(1024, Duration::from_secs(1))),
+            client_id,
+            String::from("1"),
+            String::from("jobs"),
+            Some(String::from("workers")),
+            false,
+        ))
+    };
+
+    // 已经用完的订阅还在队列组里面, 挑中它的时候要换成旁边的订阅
+    let exhausted: Arc<Subscription> = new_subscription(1);
+    exhausted.set_max_msgs(1);
+    assert!(exhausted.take());
+    let live: Arc<Subscription> = new_subscription(2);
+    live.set_max_msgs(4);
+    for subscription in &[&exhausted, &live] {
+        sub_list
+            .write()
+            .unwrap()
+            .subscribe(String::from("jobs"), Some(String::from("workers")), Arc::clone(subscription))
+            .unwrap();
+    }
+
+    for _ in 0..4 {
+        Service::deliver(&sub_list, "jobs", None, None, Bytes::from_static(b"hi"), None);
+    }
+    assert!(live.is_exhausted());
+}
+
+#[tokio::test]
+async fn service_close_cleanup() {
+    use tokio::io::AsyncWriteExt;
+
+    let (addr, sub_list) = test_server().await;
+    let mut client = TcpStream::connect(addr).await.unwrap();
+
+    client
+        .write_all(b"SUB foo 1\r\nSUB foo.* 2\r\nSUB foo.> q 3\r\nPING\r\n")
+        .await
+        .unwrap();
+    test_read(&mut client).await;
+    assert!(!sub_list.read().unwrap().match_subject("foo.bar").is_empty());
+
+    // 断开连接之后, 所有的订阅都要被删除
+    drop(client);
+    tokio::time::sleep(Duration::from_millis(300)).await;
+
+    let sub_list = sub_list.read().unwrap();
+    assert!(sub_list.match_subject("foo").is_empty());
+    assert!(sub_list.match_subject("foo.bar").is_empty());
+}
+
+#[tokio::test]
+async fn service_protocol_error() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let (addr, _) = test_server().await;
+    let mut client = TcpStream::connect(addr).await.unwrap();
+
+    // subject不合法, 回复错误之后还可以继续使用
+    client
+        .write_all(b"SUB foo.*bar 1\r\nSUB foo 2\r\nPUB foo 2\r\nhi\r\n")
+        .await
+        .unwrap();
+    let result: Vec<u8> = test_read(&mut client).await;
+    assert_eq!(test_count(&result, b"-ERR 'Invalid Subject'\r\n"), 1);
+    assert_eq!(test_count(&result, b"MSG foo 2 2\r\nhi\r\n"), 1);
+
+    // 不认识的协议, 回复错误之后断开连接
+    client.write_all(b"HELLO\r\n").await.unwrap();
+    let result: Vec<u8> = test_read(&mut client).await;
+    assert!(result.ends_with(b"-ERR 'Unknown Protocol Operation'\r\n"));
+
+    let mut buff: Vec<u8> = vec![0; 64];
+    assert_eq!(client.read(&mut buff).await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn service_ping_stale_connection() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let (addr, _) = test_server_with(|service| {
+        service.ping_interval = Duration::from_millis(100);
+        service.max_pings_outstanding = 2;
+    })
+    .await;
+
+    // 回复了PONG的连接不会被断开, 收到PONG也不会再回复PING
+    let mut client = TcpStream::connect(addr).await.unwrap();
+    let mut result: Vec<u8> = Vec::new();
+    let mut buff: Vec<u8> = vec![0; 512];
+    for _ in 0..6 {
+        if let Ok(Ok(size)) =
+            tokio::time::timeout(Duration::from_millis(150), client.read(&mut buff)).await
+        {
+            result.extend_from_slice(&buff[..size]);
+        }
+        client.write_all(b"PONG\r\n").await.unwrap();
+    }
+    assert!(test_count(&result, b"PING\r\n") >= 4);
+    assert_eq!(test_count(&result, b"-ERR"), 0);
+
+    // 不回复的话, 两个ping之后断开连接
+    let mut client = TcpStream::connect(addr).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(450)).await;
+    let result: Vec<u8> = test_read(&mut client).await;
+    assert_eq!(test_count(&result, b"PING\r\n"), 2);
+    assert!(result.ends_with(b"-ERR 'Stale Connection'\r\n"));
+
+    let mut buff: Vec<u8> = vec![0; 64];
+    assert_eq!(client.read(&mut buff).await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn service_ping_disabled() {
+    use tokio::io::AsyncWriteExt;
+
+    let (addr, _) = test_server_with(|service| service.ping_interval = Duration::from_secs(0)).await;
+
+    // ping_interval是0的时候不会ping客户端, 连接也不会因为没有回复PONG被断开
+    let mut client = TcpStream::connect(addr).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    client.write_all(b"PING\r\n").await.unwrap();
+    let result: Vec<u8> = test_read(&mut client).await;
+    assert_eq!(test_count(&result, b"PING\r\n"), 0);
+    assert!(result.ends_with(b"PONG\r\n"));
+}
+
+#[tokio::test]
+async fn service_connect_verbose() {
+    use tokio::io::AsyncWriteExt;
+
+    let (addr, _) = test_server().await;
+
+    // 没有发送verbose的话, 不会回复+OK
+    let mut client = TcpStream::connect(addr).await.unwrap();
+    client
+        .write_all(b"CONNECT {\"verbose\":false}\r\nSUB foo 1\r\nPUB foo 2\r\nhi\r\n")
+        .await
+        .unwrap();
+    let result: Vec<u8> = test_read(&mut client).await;
+    assert_eq!(test_count(&result, b"+OK\r\n"), 0);
+    assert_eq!(test_count(&result, b"MSG foo 1 2\r\nhi\r\n"), 1);
+
+    // CONNECT, SUB, PUB, UNSUB 都要回复+OK, PING只回复PONG
+    let mut client = TcpStream::connect(addr).await.unwrap();
+    client
+        .write_all(b"CONNECT {\"verbose\":true}\r\nSUB foo 1\r\nPUB foo 2\r\nhi\r\nUNSUB 1\r\nPING\r\n")
+        .await
+        .unwrap();
+    let result: Vec<u8> = test_read(&mut client).await;
+    assert_eq!(test_count(&result, b"+OK\r\n"), 4);
+    assert_eq!(test_count(&result, b"PONG\r\n"), 1);
+}
+
+#[tokio::test]
+async fn service_connect_pedantic() {
+    use tokio::io::AsyncWriteExt;
+
+    let (addr, _) = test_server().await;
+    let mut client = TcpStream::connect(addr).await.unwrap();
+
+    // pedantic模式下发布到通配符subject会回复错误, 但是不会断开连接
+    client
+        .write_all(b"CONNECT {\"pedantic\":true}\r\nSUB foo.* 1\r\nPUB foo.* 2\r\nhi\r\nPUB foo.bar 2\r\nhi\r\n")
+        .await
+        .unwrap();
+    let result: Vec<u8> = test_read(&mut client).await;
+    assert_eq!(test_count(&result, b"-ERR 'Invalid Publish Subject'\r\n"), 1);
+    assert_eq!(test_count(&result, b"MSG foo.* 1 2\r\nhi\r\n"), 0);
+    assert_eq!(test_count(&result, b"MSG foo.bar 1 2\r\nhi\r\n"), 1);
+}
+
+#[tokio::test]
+async fn service_max_payload() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let (addr, _) = test_server_with(|service| {
+        service.decode = Decode::new(512).set_max_payload(4).set_max_control_line(32);
+    })
+    .await;
+
+    // payload超过限制, 回复错误之后断开连接
+    let mut client = TcpStream::connect(addr).await.unwrap();
+    client
+        .write_all(b"SUB foo 1\r\nPUB foo 4\r\nabcd\r\nPUB foo 1000000\r\n")
+        .await
+        .unwrap();
+    let result: Vec<u8> = test_read(&mut client).await;
+    assert_eq!(test_count(&result, b"MSG foo 1 4\r\nabcd\r\n"), 1);
+    assert!(result.ends_with(b"-ERR 'Maximum Payload Violation'\r\n"));
+
+    let mut buff: Vec<u8> = vec![0; 64];
+    assert_eq!(client.read(&mut buff).await.unwrap(), 0);
+
+    // 一直不发送换行符的话, 也要断开连接
+    let mut client = TcpStream::connect(addr).await.unwrap();
+    client.write_all(&[b'a'; 64]).await.unwrap();
+    let result: Vec<u8> = test_read(&mut client).await;
+    assert!(result.ends_with(b"-ERR 'Maximum Control Line Exceeded'\r\n"));
+}
+
+#[tokio::test]
+async fn service_headers() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let (addr, _) = test_server().await;
+
+    let mut with_headers = TcpStream::connect(addr).await.unwrap();
+    let mut without_headers = TcpStream::connect(addr).await.unwrap();
+    with_headers
+        .write_all(b"CONNECT {\"headers\":true}\r\nSUB foo 1\r\n")
+        .await
+        .unwrap();
+    without_headers.write_all(b"SUB foo 2\r\n").await.unwrap();
+    let result: Vec<u8> = test_read(&mut with_headers).await;
+    assert_eq!(test_count(&result, b"\"headers\":true"), 1);
+    test_read(&mut without_headers).await;
+
+    // 支持header的连接收到HMSG, 不支持的连接收到去掉header之后的MSG
+    with_headers
+        .write_all(b"HPUB foo bar 18 20\r\nNATS/1.0\r\nA: 1\r\n\r\nhi\r\n")
+        .await
+        .unwrap();
+    let result: Vec<u8> = test_read(&mut with_headers).await;
+    assert_eq!(
+        test_count(&result, b"HMSG foo 1 bar 18 20\r\nNATS/1.0\r\nA: 1\r\n\r\nhi\r\n"),
+        1
+    );
+    let result: Vec<u8> = test_read(&mut without_headers).await;
+    assert_eq!(test_count(&result, b"MSG foo 2 bar 2\r\nhi\r\n"), 1);
+    assert_eq!(test_count(&result, b"HMSG"), 0);
+
+    // 没有声明支持header的连接不能发送HPUB
+    without_headers
+        .write_all(b"HPUB foo 12 14\r\nNATS/1.0\r\n\r\nhi\r\n")
+        .await
+        .unwrap();
+    let result: Vec<u8> = test_read(&mut without_headers).await;
+    assert!(result.ends_with(b"-ERR 'Unknown Protocol Operation'\r\n"));
+
+    let mut buff: Vec<u8> = vec![0; 64];
+    assert_eq!(without_headers.read(&mut buff).await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn service_connect_no_echo() {
+    use tokio::io::AsyncWriteExt;
+
+    let (addr, _) = test_server().await;
+    let mut publisher = TcpStream::connect(addr).await.unwrap();
+    let mut other = TcpStream::connect(addr).await.unwrap();
+
+    // 每个连接的nonce都不一样
+    let publisher_info: Vec<u8> = test_read(&mut publisher).await;
+    let other_info: Vec<u8> = test_read(&mut other).await;
+    assert_eq!(test_count(&publisher_info, b"\"nonce\":\""), 1);
+    assert_eq!(test_count(&publisher_info, b"\"proto\":1"), 1);
+    // 没有配置认证的话不需要认证
+    assert_eq!(test_count(&publisher_info, b"\"auth_required\":false"), 1);
+    assert_ne!(publisher_info, other_info);
+
+    publisher
+        .write_all(b"CONNECT {\"echo\":false}\r\nSUB foo 1\r\nSUB foo q 2\r\nPING\r\n")
+        .await
+        .unwrap();
+    other.write_all(b"SUB foo 3\r\nSUB foo q 4\r\nPING\r\n").await.unwrap();
+    test_read(&mut publisher).await;
+    test_read(&mut other).await;
+
+    // 自己的订阅不会收到, 队列组也只会选其他连接的订阅
+    for _ in 0..4 {
+        publisher.write_all(b"PUB foo 2\r\nhi\r\n").await.unwrap();
+    }
+    let result: Vec<u8> = test_read(&mut publisher).await;
+    assert_eq!(test_count(&result, b"MSG"), 0);
+    let result: Vec<u8> = test_read(&mut other).await;
+    assert_eq!(test_count(&result, b"MSG foo 3 2\r\nhi\r\n"), 4);
+    assert_eq!(test_count(&result, b"MSG foo 4 2\r\nhi\r\n"), 4);
+
+    // 没有设置echo的连接会收到自己发布的消息
+    other.write_all(b"PUB foo 2\r\nhi\r\n").await.unwrap();
+    let result: Vec<u8> = test_read(&mut other).await;
+    assert_eq!(test_count(&result, b"MSG foo 3 2\r\nhi\r\n"), 1);
+    let result: Vec<u8> = test_read(&mut publisher).await;
+    assert_eq!(test_count(&result, b"MSG foo 1 2\r\nhi\r\n"), 1);
+}
+
+// 订阅之后一直不读取, 让服务端的发送缓冲区堆积起来
+#[cfg(test)]
+async fn test_slow_consumer<F>(setup: F) -> Vec<u8>
+where
+    F: Fn(&mut Service) + Send + 'static,
+{
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::time::timeout;
+
+    let (addr, _) = test_server_with(setup).await;
+    let mut slow = TcpStream::connect(addr).await.unwrap();
+    let mut publisher = TcpStream::connect(addr).await.unwrap();
+    slow.write_all(b"SUB foo 1\r\nPING\r\n").await.unwrap();
+    test_read(&mut slow).await;
+
+    // 超过了socket的缓冲区大小, 写入任务就会阻塞
+    let mut message: Vec<u8> = b"PUB foo 60000\r\n".to_vec();
+    message.extend_from_slice(&[b'a'; 60000]);
+    message.extend_from_slice(b"\r\n");
+    for _ in 0..600 {
+        publisher.write_all(&message).await.unwrap();
+    }
+
+    // 发布者不会受到影响
+    publisher.write_all(b"PING\r\n").await.unwrap();
+    let result: Vec<u8> = test_read(&mut publisher).await;
+    assert!(result.ends_with(b"PONG\r\n"));
+
+    // 慢消费者被断开连接
+    let mut result: Vec<u8> = Vec::new();
+    timeout(Duration::from_secs(10), slow.read_to_end(&mut result))
+        .await
+        .unwrap()
+        .unwrap();
+    result
+}
+
+#[tokio::test]
+async fn service_slow_consumer_max_pending() {
+    use crate::global_static::STATS;
+
+    let slow_consumers: u64 = STATS.get_slow_consumers();
+    let result: Vec<u8> = test_slow_consumer(|service| {
+        service.outbound = Arc::new(Outbound::new(256 * 1024, Duration::from_secs(5)));
+    })
+    .await;
+    assert!(result.ends_with(b"-ERR 'Slow Consumer'\r\n"));
+    assert!(STATS.get_slow_consumers() > slow_consumers);
+}
+
+#[tokio::test]
+async fn service_slow_consumer_write_deadline() {
+    use crate::global_static::STATS;
+
+    let slow_consumers: u64 = STATS.get_slow_consumers();
+    let result: Vec<u8> = test_slow_consumer(|service| {
+        service.outbound = Arc::new(Outbound::new(usize::MAX, Duration::from_millis(200)));
+    })
+    .await;
+    // 超时的时候可能有写了一半的消息, 不能再发送错误
+    assert_eq!(test_count(&result, b"-ERR"), 0);
+    assert!(STATS.get_slow_consumers() > slow_consumers);
+}
+
+#[tokio::test]
+async fn service_tls_upgrade() {
+    use super::tls::test_tls;
+    use std::convert::TryFrom;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio_rustls::rustls::pki_types::ServerName;
+
+    let (tls, connector, _) = test_tls("");
+    let (addr, _) = test_server_with(move |service| service.tls = Some(tls.clone())).await;
+
+    // 先用明文收到INFO, 里面要求客户端使用TLS
+    let mut client = BufReader::new(TcpStream::connect(addr).await.unwrap());
+    let mut info: String = String::new();
+    client.read_line(&mut info).await.unwrap();
+    assert!(info.starts_with("INFO "));
+    assert!(info.contains("\"tls_required\":true"));
+    assert!(info.contains("\"ssl_required\":true"));
+
+    // 握手之后才开始解析协议
+    let mut client = connector
+        .connect(ServerName::try_from("localhost").unwrap(), client.into_inner())
+        .await
+        .unwrap();
+    client
+        .write_all(b"CONNECT {}\r\nSUB foo 1\r\nPUB foo 2\r\nhi\r\nPING\r\n")
+        .await
+        .unwrap();
+    let result: Vec<u8> = test_read(&mut client).await;
+    assert_eq!(result, b"MSG foo 1 2\r\nhi\r\nPONG\r\n");
+}
+
+#[tokio::test]
+async fn service_tls_first() {
+    use super::tls::test_tls;
+    use std::convert::TryFrom;
+    use tokio::io::AsyncWriteExt;
+    use tokio_rustls::rustls::pki_types::ServerName;
+
+    let (tls, connector, _) = test_tls("tls_first = true");
+    let (addr, _) = test_server_with(move |service| service.tls = Some(tls.clone())).await;
+
+    // 连接之后马上握手, INFO是在TLS里面发送的
+    let client = TcpStream::connect(addr).await.unwrap();
+    let mut client = connector
+        .connect(ServerName::try_from("localhost").unwrap(), client)
+        .await
+        .unwrap();
+    client.write_all(b"PING\r\n").await.unwrap();
+    let result: Vec<u8> = test_read(&mut client).await;
+    assert!(result.starts_with(b"INFO "));
+    assert!(result.ends_with(b"PONG\r\n"));
+}
+
+#[tokio::test]
+async fn service_tls_handshake_timeout() {
+    use super::tls::test_tls;
+    use std::time::Instant;
+    use tokio::io::AsyncReadExt;
+    use tokio::time::timeout;
+
+    let (tls, _, _) = test_tls("timeout = 0.5");
+    let (addr, _) = test_server_with(move |service| service.tls = Some(tls.clone())).await;
+
+    // 收到INFO之后什么都不发送, 不握手的话超时之后断开连接
+    let mut client = TcpStream::connect(addr).await.unwrap();
+    let start: Instant = Instant::now();
+    let mut info: Vec<u8> = Vec::new();
+    let mut buff: Vec<u8> = vec![0; 4096];
+    while !info.ends_with(b"\r\n") {
+        let size: usize = client.read(&mut buff).await.unwrap();
+        assert_ne!(size, 0);
+        info.extend_from_slice(&buff[..size]);
+    }
+    assert!(info.starts_with(b"INFO "));
+
+    let mut buff: Vec<u8> = Vec::new();
+    let result = timeout(Duration::from_secs(3), client.read_to_end(&mut buff)).await;
+    let elapsed: Duration = start.elapsed();
+    assert!(matches!(result, Ok(Ok(0))));
+    assert!(elapsed >= Duration::from_millis(500), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_millis(1500), "{:?}", elapsed);
+}
+
+// 用TLS连接到测试服务, 发送数据之后返回收到的所有数据
+#[cfg(test)]
+async fn test_tls_request(
+    addr: SocketAddr,
+    connector: &tokio_rustls::TlsConnector,
+    request: &[u8],
+) -> Vec<u8> {
+    use std::convert::TryFrom;
+    use tokio::io::AsyncWriteExt;
+    use tokio_rustls::rustls::pki_types::ServerName;
+
+    let client = TcpStream::connect(addr).await.unwrap();
+    match connector
+        .connect(ServerName::try_from("localhost").unwrap(), client)
+        .await
+    {
+        Ok(mut client) => {
+            // TLS1.3的客户端证书是握手之后才检查的, 被拒绝的话这里的读写会出错
+            let _ = client.write_all(request).await;
+            test_read(&mut client).await
+        }
+        Err(_) => Vec::new(),
     }
+}
+
+#[tokio::test]
+async fn service_tls_verify() {
+    use super::tls::test_tls;
+
+    let (tls, connector, cert_connector) = test_tls("tls_first = true\nverify = true");
+    let (addr, _) = test_server_with(move |service| service.tls = Some(tls.clone())).await;
+
+    // 没有客户端证书的话握手失败
+    let result: Vec<u8> = test_tls_request(addr, &connector, b"CONNECT {}\r\nPING\r\n").await;
+    assert_eq!(test_count(&result, b"PONG\r\n"), 0);
+
+    // 只要求证书, 不需要对应用户
+    let result: Vec<u8> = test_tls_request(addr, &cert_connector, b"CONNECT {}\r\nPING\r\n").await;
+    assert!(result.ends_with(b"PONG\r\n"));
+}
+
+#[tokio::test]
+async fn service_tls_verify_and_map() {
+    use super::tls::test_tls;
+    use crate::config::AuthorizationConfig;
+
+    let (tls, _, cert_connector) = test_tls("tls_first = true\nverify_and_map = true");
+    let setup = |users: &str| {
+        let tls: Tls = tls.clone();
+        let config: AuthorizationConfig = toml::from_str(users).unwrap();
+        let auth: Arc<Auth> = Arc::new(Auth::new(Some(&config)).unwrap());
+        move |service: &mut Service| {
+            service.tls = Some(tls.clone());
+            service.auth = auth.clone();
+        }
+    };
+
+    // 证书的subject对应了配置的用户
+    let (addr, _) = test_server_with(setup("users = [{ user = \"CN=client,O=beaver\" }]")).await;
+    let result: Vec<u8> =
+        test_tls_request(addr, &cert_connector, b"CONNECT {}\r\nSUB foo 1\r\nPUB foo 2\r\nhi\r\nPING\r\n").await;
+    assert!(result.starts_with(b"INFO "));
+    assert_eq!(test_count(&result, b"\"auth_required\":true"), 1);
+    assert!(result.ends_with(b"MSG foo 1 2\r\nhi\r\nPONG\r\n"));
+
+    // 没有发送CONNECT之前不能执行其他操作
+    let result: Vec<u8> = test_tls_request(addr, &cert_connector, b"SUB foo 1\r\nPING\r\n").await;
+    assert!(result.ends_with(b"-ERR 'Authorization Violation'\r\n"));
+
+    // email也可以对应用户
+    let (addr, _) = test_server_with(setup("users = [{ user = \"client@beaver.io\" }]")).await;
+    let result: Vec<u8> = test_tls_request(addr, &cert_connector, b"CONNECT {}\r\nPING\r\n").await;
+    assert!(result.ends_with(b"PONG\r\n"));
+
+    // 证书没有对应任何用户
+    let (addr, sub_list) = test_server_with(setup("users = [{ user = \"CN=other\" }]")).await;
+    let result: Vec<u8> =
+        test_tls_request(addr, &cert_connector, b"CONNECT {}\r\nSUB foo 1\r\nPING\r\n").await;
+    assert!(result.ends_with(b"-ERR 'Authorization Violation'\r\n"));
+    assert!(sub_list.read().unwrap().match_subject("foo").is_empty());
+}
+
+// 使用指定的认证配置启动测试服务
+#[cfg(test)]
+async fn test_auth_server(config: &str) -> (SocketAddr, ArcSubList) {
+    use crate::config::AuthorizationConfig;
+
+    let config: AuthorizationConfig = toml::from_str(config).unwrap();
+    let auth: Arc<Auth> = Arc::new(Auth::new(Some(&config)).unwrap());
+    test_server_with(move |service| service.auth = auth.clone()).await
+}
 
-    async fn send_pong(&mut self) -> IoResult<()> {
-        self.write_stream.lock().await.write(Pong::format()).await
+#[tokio::test]
+async fn service_auth_token() {
+    use tokio::io::AsyncWriteExt;
+
+    let (addr, sub_list) = test_auth_server("token = \"s3cr3t\"").await;
+
+    let mut client = TcpStream::connect(addr).await.unwrap();
+    client
+        .write_all(b"CONNECT {\"auth_token\":\"s3cr3t\"}\r\nSUB foo 1\r\nPUB foo 2\r\nhi\r\nPING\r\n")
+        .await
+        .unwrap();
+    let result: Vec<u8> = test_read(&mut client).await;
+    assert_eq!(test_count(&result, b"\"auth_required\":true"), 1);
+    assert!(result.ends_with(b"MSG foo 1 2\r\nhi\r\nPONG\r\n"));
+
+    // token不对的话, 后面的操作都不会执行
+    let mut client = TcpStream::connect(addr).await.unwrap();
+    client
+        .write_all(b"CONNECT {\"auth_token\":\"wrong\"}\r\nSUB bar 1\r\nPING\r\n")
+        .await
+        .unwrap();
+    let result: Vec<u8> = test_read(&mut client).await;
+    assert!(result.ends_with(b"-ERR 'Authorization Violation'\r\n"));
+    assert!(sub_list.read().unwrap().match_subject("bar").is_empty());
+
+    // 没有CONNECT就执行其他操作
+    let mut client = TcpStream::connect(addr).await.unwrap();
+    client.write_all(b"PUB foo 2\r\nhi\r\n").await.unwrap();
+    let result: Vec<u8> = test_read(&mut client).await;
+    assert!(result.ends_with(b"-ERR 'Authorization Violation'\r\n"));
+}
+
+#[tokio::test]
+async fn service_auth_users() {
+    use tokio::io::AsyncWriteExt;
+
+    let hash: String = bcrypt::hash("bar", 4).unwrap();
+    let (addr, _) = test_auth_server(&format!(
+        "users = [{{ user = \"alice\", password = \"foo\" }}, {{ user = \"bob\", password = {:?} }}]",
+        hash
+    ))
+    .await;
+
+    for connect in &[
+        &b"CONNECT {\"verbose\":true,\"user\":\"alice\",\"pass\":\"foo\"}\r\nPING\r\n"[..],
+        &b"CONNECT {\"verbose\":true,\"user\":\"bob\",\"pass\":\"bar\"}\r\nPING\r\n"[..],
+    ] {
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(connect).await.unwrap();
+        let result: Vec<u8> = test_read(&mut client).await;
+        assert!(result.ends_with(b"+OK\r\nPONG\r\n"));
     }
+
+    let mut client = TcpStream::connect(addr).await.unwrap();
+    client
+        .write_all(b"CONNECT {\"user\":\"bob\",\"pass\":\"foo\"}\r\nPING\r\n")
+        .await
+        .unwrap();
+    let result: Vec<u8> = test_read(&mut client).await;
+    assert!(result.ends_with(b"-ERR 'Authorization Violation'\r\n"));
+}
+
+#[tokio::test]
+async fn service_auth_timeout() {
+    use tokio::io::AsyncReadExt;
+    use tokio::time::timeout;
+
+    let (addr, _) = test_auth_server("token = \"s3cr3t\"\ntimeout = 0.2").await;
+
+    // 一直不发送CONNECT的话, 超时之后断开连接
+    let mut client = TcpStream::connect(addr).await.unwrap();
+    let mut result: Vec<u8> = Vec::new();
+    timeout(Duration::from_secs(2), client.read_to_end(&mut result))
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(result.starts_with(b"INFO "));
+    assert!(result.ends_with(b"-ERR 'Authentication Timeout'\r\n"));
+}
+
+#[tokio::test]
+async fn service_auth_nkey() {
+    use data_encoding::BASE64URL_NOPAD;
+    use nkeys::KeyPair;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    let key_pair: KeyPair = KeyPair::new_user();
+    let (addr, _) = test_auth_server(&format!("nkeys = [{{ nkey = {:?} }}]", key_pair.public_key())).await;
+
+    // 用INFO里面的nonce签名
+    for (key_pair, expected) in &[
+        (&key_pair, &b"PONG\r\n"[..]),
+        (&KeyPair::new_user(), &b"-ERR 'Authorization Violation'\r\n"[..]),
+    ] {
+        let mut client = BufReader::new(TcpStream::connect(addr).await.unwrap());
+        let mut info: String = String::new();
+        client.read_line(&mut info).await.unwrap();
+        let info: serde_json::Value = serde_json::from_str(&info["INFO ".len()..]).unwrap();
+        let nonce: &str = info["nonce"].as_str().unwrap();
+        let sig: String = BASE64URL_NOPAD.encode(&key_pair.sign(nonce.as_bytes()).unwrap());
+
+        client
+            .write_all(
+                format!(
+                    "CONNECT {{\"nkey\":{:?},\"sig\":{:?}}}\r\nPING\r\n",
+                    key_pair.public_key(),
+                    sig
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+        let result: Vec<u8> = test_read(&mut client).await;
+        assert_eq!(result, *expected);
+    }
+}
+
+// 用INFO里面的nonce签名, 带着JWT发送CONNECT
+#[cfg(test)]
+async fn test_jwt_connect(
+    addr: SocketAddr,
+    jwt: &str,
+    key_pair: &nkeys::KeyPair,
+) -> tokio::io::BufReader<TcpStream> {
+    use data_encoding::BASE64URL_NOPAD;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    let mut client = BufReader::new(TcpStream::connect(addr).await.unwrap());
+    let mut info: String = String::new();
+    client.read_line(&mut info).await.unwrap();
+    let info: serde_json::Value = serde_json::from_str(&info["INFO ".len()..]).unwrap();
+    let sig: String = BASE64URL_NOPAD.encode(&key_pair.sign(info["nonce"].as_str().unwrap().as_bytes()).unwrap());
+    client
+        .write_all(format!("CONNECT {{\"jwt\":{:?},\"sig\":{:?}}}\r\n", jwt, sig).as_bytes())
+        .await
+        .unwrap();
+    client
+}
+
+#[tokio::test]
+async fn service_auth_jwt() {
+    use super::jwt::{test_encode, test_operator, Operator};
+    use nkeys::KeyPair;
+    use serde_json::json;
+    use tokio::io::AsyncWriteExt;
+
+    let account: KeyPair = KeyPair::new_account();
+    let (config, _jwt_file) = test_operator(&account, json!({}));
+    let operator: Operator = Operator::new(&config).unwrap();
+    let auth: Arc<Auth> = Arc::new(Auth::new(None).unwrap().set_operator(operator));
+    let (addr, sub_list) = test_server_with(move |service| service.auth = auth.clone()).await;
+
+    let user: KeyPair = KeyPair::new_user();
+    let jwt: String = test_encode(
+        &account,
+        json!({"iss": account.public_key(), "sub": user.public_key(), "nats": {
+            "type": "user", "pub": {"allow": ["orders.>"]}, "sub": {"deny": ["secret.>"]}, "subs": 1, "payload": 8,
+        }}),
+    );
+
+    // 没有权限的话只返回错误, 不会断开连接
+    let mut client = test_jwt_connect(addr, &jwt, &user).await;
+    client
+        .write_all(b"SUB secret.key 1\r\nSUB orders.* 2\r\nSUB orders.> 3\r\nPUB payments.created 2\r\nhi\r\nPUB orders.created 2\r\nhi\r\nPING\r\n")
+        .await
+        .unwrap();
+    let result: Vec<u8> = test_read(&mut client).await;
+    assert_eq!(
+        result,
+        &b"-ERR 'Permissions Violation for Subscription to secret.key'\r\n\
+        -ERR 'Maximum Subscriptions Exceeded'\r\n\
+        -ERR 'Permissions Violation for Publish to payments.created'\r\n\
+        MSG orders.created 2 2\r\nhi\r\n\
+        PONG\r\n"[..]
+    );
+    assert!(sub_list.read().unwrap().match_subject("secret.key").is_empty());
+
+    // 超过用户的最大payload要断开连接
+    client.write_all(b"PUB orders.created 9\r\n123456789\r\n").await.unwrap();
+    let result: Vec<u8> = test_read(&mut client).await;
+    assert_eq!(result, b"-ERR 'Maximum Payload Violation'\r\n");
+
+    // 签名的私钥和JWT里面的用户不一样
+    let mut client = test_jwt_connect(addr, &jwt, &KeyPair::new_user()).await;
+    client.write_all(b"PING\r\n").await.unwrap();
+    let result: Vec<u8> = test_read(&mut client).await;
+    assert_eq!(result, b"-ERR 'Authorization Violation'\r\n");
+
+    // bearer的JWT不需要签名
+    let jwt: String = test_encode(
+        &account,
+        json!({"iss": account.public_key(), "sub": user.public_key(), "nats": {"type": "user", "bearer_token": true}}),
+    );
+    let mut client = test_jwt_connect(addr, &jwt, &KeyPair::new_user()).await;
+    client.write_all(b"PING\r\n").await.unwrap();
+    let result: Vec<u8> = test_read(&mut client).await;
+    assert_eq!(result, b"PONG\r\n");
+}
+
+#[tokio::test]
+async fn service_auth_jwt_max_subs() {
+    use super::jwt::{test_encode, test_operator, Operator};
+    use nkeys::KeyPair;
+    use serde_json::json;
+    use tokio::io::AsyncWriteExt;
+
+    let account: KeyPair = KeyPair::new_account();
+    let (config, _jwt_file) = test_operator(&account, json!({}));
+    let operator: Operator = Operator::new(&config).unwrap();
+    let auth: Arc<Auth> = Arc::new(Auth::new(None).unwrap().set_operator(operator));
+    let (addr, _) = test_server_with(move |service| service.auth = auth.clone()).await;
+
+    let user: KeyPair = KeyPair::new_user();
+    let jwt: String = test_encode(
+        &account,
+        json!({"iss": account.public_key(), "sub": user.public_key(), "nats": {"type": "user", "subs": 1}}),
+    );
+    let mut client = test_jwt_connect(addr, &jwt, &user).await;
+
+    // 自动取消订阅已经发送够了的inbox不算在最大订阅数量里面
+    for sid in 1..=3 {
+        client
+            .write_all(format!("SUB _INBOX.{0} {0}\r\nUNSUB {0} 1\r\nPUB _INBOX.{0} 2\r\nhi\r\n", sid).as_bytes())
+            .await
+            .unwrap();
+    }
+    client.write_all(b"SUB foo 4\r\nSUB bar 5\r\nPING\r\n").await.unwrap();
+    let result: Vec<u8> = test_read(&mut client).await;
+    assert_eq!(test_count(&result, b" 2\r\nhi\r\n"), 3);
+    assert!(result.ends_with(b"-ERR 'Maximum Subscriptions Exceeded'\r\nPONG\r\n"));
+    assert_eq!(test_count(&result, b"-ERR"), 1);
+}
+
+#[tokio::test]
+async fn service_auth_jwt_expired() {
+    use super::jwt::{test_encode, test_operator, Operator};
+    use nkeys::KeyPair;
+    use serde_json::json;
+    use std::time::UNIX_EPOCH;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::time::timeout;
+
+    let account: KeyPair = KeyPair::new_account();
+    let (config, _jwt_file) = test_operator(&account, json!({}));
+    let operator: Operator = Operator::new(&config).unwrap();
+    let auth: Arc<Auth> = Arc::new(Auth::new(None).unwrap().set_operator(operator));
+    let (addr, _) = test_server_with(move |service| service.auth = auth.clone()).await;
+
+    // JWT过期之后断开连接
+    let user: KeyPair = KeyPair::new_user();
+    let exp: u64 = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() + 2;
+    let jwt: String = test_encode(
+        &account,
+        json!({"iss": account.public_key(), "sub": user.public_key(), "exp": exp, "nats": {"type": "user"}}),
+    );
+    let mut client = test_jwt_connect(addr, &jwt, &user).await;
+    client.write_all(b"PING\r\n").await.unwrap();
+    let mut result: Vec<u8> = Vec::new();
+    timeout(Duration::from_secs(4), client.read_to_end(&mut result))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(result, b"PONG\r\n-ERR 'User Authentication Expired'\r\n");
+}
+
+#[tokio::test]
+async fn service_auth_permissions() {
+    use tokio::io::AsyncWriteExt;
+
+    let (addr, sub_list) = test_auth_server(
+        "users = [{ user = \"orders\", password = \"foo\", permissions = { publish = { allow = [\"orders.>\"], deny = [\"orders.internal.>\"] }, subscribe = { allow = [\"orders.*\"] } } }]",
+    )
+    .await;
+
+    // 没有权限的发布和订阅都只返回错误, 连接可以继续使用
+    let mut client = TcpStream::connect(addr).await.unwrap();
+    client
+        .write_all(
+            b"CONNECT {\"user\":\"orders\",\"pass\":\"foo\"}\r\n\
+            SUB orders.> 1\r\nSUB payments.* 2\r\nSUB orders.* 3\r\n\
+            PUB payments.created 2\r\nhi\r\nPUB orders.internal.audit 2\r\nhi\r\n\
+            PUB orders.created 2\r\nhi\r\nPUB payments.created 2\r\nhi\r\nPING\r\n",
+        )
+        .await
+        .unwrap();
+    let result: Vec<u8> = test_read(&mut client).await;
+    assert!(result.ends_with(
+        b"-ERR 'Permissions Violation for Subscription to orders.>'\r\n\
+        -ERR 'Permissions Violation for Subscription to payments.*'\r\n\
+        -ERR 'Permissions Violation for Publish to payments.created'\r\n\
+        -ERR 'Permissions Violation for Publish to orders.internal.audit'\r\n\
+        MSG orders.created 3 2\r\nhi\r\n\
+        -ERR 'Permissions Violation for Publish to payments.created'\r\n\
+        PONG\r\n"
+    ));
+    assert_eq!(sub_list.read().unwrap().match_subject("orders.created").get_subs().len(), 1);
+    assert!(sub_list.read().unwrap().match_subject("payments.created").is_empty());
+
+    // 通配符的订阅不能绕过deny
+    let (addr, sub_list) = test_auth_server(
+        "users = [{ user = \"alice\", password = \"foo\", permissions = { subscribe = { deny = [\"secret.*\"] } } }]",
+    )
+    .await;
+    let mut client = TcpStream::connect(addr).await.unwrap();
+    client
+        .write_all(b"CONNECT {\"user\":\"alice\",\"pass\":\"foo\"}\r\nSUB > 1\r\nSUB *.key 2\r\nPUB secret.key 2\r\nhi\r\nPING\r\n")
+        .await
+        .unwrap();
+    let result: Vec<u8> = test_read(&mut client).await;
+    assert!(result.ends_with(
+        b"-ERR 'Permissions Violation for Subscription to >'\r\n\
+        -ERR 'Permissions Violation for Subscription to *.key'\r\n\
+        PONG\r\n"
+    ));
+    assert!(sub_list.read().unwrap().match_subject("secret.key").is_empty());
+}
+
+#[tokio::test]
+async fn service_auth_allow_responses() {
+    use tokio::io::AsyncWriteExt;
+
+    let (addr, _) = test_auth_server(
+        "users = [\
+        { user = \"service\", password = \"foo\", permissions = { publish = { allow = [\"events.>\"] }, subscribe = { allow = [\"requests.>\"] }, allow_responses = { max = 1 } } },\
+        { user = \"client\", password = \"bar\" },\
+        ]",
+    )
+    .await;
+
+    let mut service = TcpStream::connect(addr).await.unwrap();
+    service
+        .write_all(b"CONNECT {\"user\":\"service\",\"pass\":\"foo\"}\r\nSUB requests.> 1\r\nPING\r\n")
+        .await
+        .unwrap();
+    let mut client = TcpStream::connect(addr).await.unwrap();
+    client
+        .write_all(b"CONNECT {\"user\":\"client\",\"pass\":\"bar\"}\r\nSUB _INBOX.a 1\r\nSUB _INBOX.b 2\r\nPING\r\n")
+        .await
+        .unwrap();
+    test_read(&mut service).await;
+    test_read(&mut client).await;
+
+    // 没有收到过的reply不能发布
+    client.write_all(b"PUB requests.time _INBOX.a 2\r\nhi\r\n").await.unwrap();
+    let result: Vec<u8> = test_read(&mut service).await;
+    assert_eq!(result, b"MSG requests.time 1 _INBOX.a 2\r\nhi\r\n");
+    service
+        .write_all(b"PUB _INBOX.b 2\r\nno\r\nPUB _INBOX.a 2\r\nok\r\nPUB _INBOX.a 2\r\nok\r\nPING\r\n")
+        .await
+        .unwrap();
+    let result: Vec<u8> = test_read(&mut service).await;
+    assert_eq!(
+        result,
+        &b"-ERR 'Permissions Violation for Publish to _INBOX.b'\r\n\
+        -ERR 'Permissions Violation for Publish to _INBOX.a'\r\n\
+        PONG\r\n"[..]
+    );
+    let result: Vec<u8> = test_read(&mut client).await;
+    assert_eq!(result, b"MSG _INBOX.a 1 2\r\nok\r\n");
+}
+
+#[tokio::test]
+async fn service_auth_allow_responses_reconnect() {
+    use tokio::io::AsyncWriteExt;
+
+    let (addr, _) = test_auth_server(
+        "users = [\
+        { user = \"service\", password = \"foo\", permissions = { publish = { allow = [\"events.>\"] }, subscribe = { allow = [\"requests.>\"] }, allow_responses = {} } },\
+        { user = \"client\", password = \"bar\" },\
+        ]",
+    )
+    .await;
+
+    let mut service = TcpStream::connect(addr).await.unwrap();
+    service
+        .write_all(b"CONNECT {\"user\":\"service\",\"pass\":\"foo\"}\r\nSUB requests.> 1\r\nPING\r\n")
+        .await
+        .unwrap();
+    let mut client = TcpStream::connect(addr).await.unwrap();
+    client
+        .write_all(b"CONNECT {\"user\":\"client\",\"pass\":\"bar\"}\r\nSUB _INBOX.a 1\r\nPING\r\n")
+        .await
+        .unwrap();
+    test_read(&mut service).await;
+    test_read(&mut client).await;
+
+    client.write_all(b"PUB requests.time _INBOX.a 2\r\nhi\r\n").await.unwrap();
+    let result: Vec<u8> = test_read(&mut service).await;
+    assert_eq!(result, b"MSG requests.time 1 _INBOX.a 2\r\nhi\r\n");
+
+    // 重新CONNECT之后, 原来的订阅收到的reply还是可以回复
+    service
+        .write_all(b"CONNECT {\"user\":\"service\",\"pass\":\"foo\"}\r\nPUB _INBOX.a 2\r\nok\r\nPING\r\n")
+        .await
+        .unwrap();
+    let result: Vec<u8> = test_read(&mut service).await;
+    assert_eq!(result, b"PONG\r\n");
+    let result: Vec<u8> = test_read(&mut client).await;
+    assert_eq!(result, b"MSG _INBOX.a 1 2\r\nok\r\n");
+}
+
+#[tokio::test]
+async fn service_accounts() {
+    use crate::config::{AccountConfig, AuthorizationConfig};
+    use tokio::io::AsyncWriteExt;
+
+    let config: AuthorizationConfig = toml::from_str("users = [{ user = \"alice\", password = \"foo\" }]").unwrap();
+    let orders: AccountConfig = toml::from_str(
+        "users = [{ user = \"bob\", password = \"bar\" }, { user = \"carol\", password = \"baz\" }]",
+    )
+    .unwrap();
+    let payments: AccountConfig = toml::from_str("users = [{ user = \"dave\", password = \"qux\" }]").unwrap();
+    let auth: Arc<Auth> = Arc::new(
+        Auth::new(Some(&config))
+            .unwrap()
+            .add_account("orders", &orders)
+            .unwrap()
+            .add_account("payments", &payments)
+            .unwrap(),
+    );
+    let (addr, sub_list) = test_server_with(move |service| service.auth = auth.clone()).await;
+
+    let mut subscribers: Vec<TcpStream> = Vec::new();
+    for (user, pass) in &[("alice", "foo"), ("bob", "bar"), ("dave", "qux")] {
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(
+                format!(
+                    "CONNECT {{\"user\":{:?},\"pass\":{:?}}}\r\nSUB orders.created 1\r\nPING\r\n",
+                    user, pass
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+        test_read(&mut client).await;
+        subscribers.push(client);
+    }
+    // 全局账户里面只有alice的订阅
+    assert_eq!(sub_list.read().unwrap().match_subject("orders.created").get_subs().len(), 1);
+
+    // 同一个subject只会发送给同一个账户的订阅
+    let mut publisher = TcpStream::connect(addr).await.unwrap();
+    publisher
+        .write_all(b"CONNECT {\"user\":\"carol\",\"pass\":\"baz\"}\r\nPUB orders.created 2\r\nhi\r\nPING\r\n")
+        .await
+        .unwrap();
+    assert!(test_read(&mut publisher).await.ends_with(b"PONG\r\n"));
+    let results: Vec<Vec<u8>> = futures::future::join_all(subscribers.iter_mut().map(test_read)).await;
+    assert!(results[0].is_empty());
+    assert_eq!(results[1], b"MSG orders.created 1 2\r\nhi\r\n");
+    assert!(results[2].is_empty());
+}
+
+#[tokio::test]
+async fn service_account_imports() {
+    use crate::config::AccountConfig;
+    use std::collections::HashMap;
+    use tokio::io::AsyncWriteExt;
+
+    let config: HashMap<String, AccountConfig> = toml::from_str(
+        r#"
+        [orders]
+        users = [{ user = "bob", password = "bar" }]
+        exports = [{ stream = "orders.>" }, { service = "orders.lookup", accounts = ["payments"] }]
+        [payments]
+        users = [{ user = "dave", password = "qux" }]
+        imports = [
+            { stream = { account = "orders", subject = "orders.>" }, prefix = "external" },
+            { service = { account = "orders", subject = "orders.lookup" }, to = "lookup" },
+        ]
+        "#,
+    )
+    .unwrap();
+    let mut auth: Auth = Auth::new(None).unwrap();
+    for (name, account) in &config {
+        auth = auth.add_account(name, account).unwrap();
+    }
+    let auth: Arc<Auth> = Arc::new(auth);
+    let accounts: Arc<Accounts> = Arc::new(Accounts::new(Some(&config)).unwrap());
+    let (addr, _) = test_server_with(move |service| {
+        service.auth = auth.clone();
+        service.accounts = accounts.clone();
+        service.account = accounts.get_global().clone();
+    })
+    .await;
+
+    let mut orders = TcpStream::connect(addr).await.unwrap();
+    orders
+        .write_all(b"CONNECT {\"user\":\"bob\",\"pass\":\"bar\"}\r\nSUB orders.lookup 1\r\nPING\r\n")
+        .await
+        .unwrap();
+    test_read(&mut orders).await;
+    let mut payments = TcpStream::connect(addr).await.unwrap();
+    payments
+        .write_all(
+            b"CONNECT {\"user\":\"dave\",\"pass\":\"qux\"}\r\nSUB external.orders.> 1\r\nSUB _INBOX.> 2\r\nPING\r\n",
+        )
+        .await
+        .unwrap();
+    test_read(&mut payments).await;
+
+    // 导入的stream加上前缀
+    orders.write_all(b"PUB orders.created 2\r\nhi\r\n").await.unwrap();
+    let result: Vec<u8> = test_read(&mut payments).await;
+    assert_eq!(result, b"MSG external.orders.created 1 2\r\nhi\r\n");
+
+    // 导入的service, 导出账户看到的是新的reply, 回复之后换回原来的reply
+    payments.write_all(b"PUB lookup _INBOX.a 2\r\nid\r\n").await.unwrap();
+    let result: String = String::from_utf8(test_read(&mut orders).await).unwrap();
+    let reply_to: &str = result
+        .strip_prefix("MSG orders.lookup 1 ")
+        .and_then(|result| result.strip_suffix(" 2\r\nid\r\n"))
+        .unwrap();
+    assert!(reply_to.starts_with("_R_."));
+    orders
+        .write_all(format!("PUB {} 2\r\nok\r\nPUB {} 2\r\nok\r\n", reply_to, reply_to).as_bytes())
+        .await
+        .unwrap();
+    let result: Vec<u8> = test_read(&mut payments).await;
+    assert_eq!(result, b"MSG _INBOX.a 2 2\r\nok\r\n");
 }
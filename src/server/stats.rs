@@ -0,0 +1,21 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// 服务的统计数据, 所有的连接都会修改, 所以都用原子变量
+#[derive(Debug, Default)]
+pub struct Stats {
+    slow_consumers: AtomicU64,
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get_slow_consumers(&self) -> u64 {
+        self.slow_consumers.load(Ordering::Relaxed)
+    }
+
+    pub(super) fn add_slow_consumer(&self) {
+        self.slow_consumers.fetch_add(1, Ordering::Relaxed);
+    }
+}
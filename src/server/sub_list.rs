@@ -1,9 +1,204 @@
+use std::collections::HashMap;
 use std::fmt::{Debug, Error as FmtError, Formatter};
 use std::iter::Iterator;
 use std::ops::FnMut;
-use std::slice::{Iter, IterMut};
+use std::slice::Iter;
+use log::debug;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, PoisonError, RwLock};
+use thiserror::Error;
+
+// 匹配一个token
+const TOKEN_WILDCARD: &str = "*";
+// 匹配后面所有的token, 只能放在最后
+const FULL_WILDCARD: &str = ">";
+
+// 匹配结果缓存最多保存的subject数量, 满了之后按照HashMap的顺序删掉一部分, 不是LRU
+const CACHE_MAX: usize = 1024;
+const CACHE_SWEEP: usize = 256;
+
+#[derive(Debug, Error, PartialEq)]
+pub(super) enum Error {
+    #[error("invalid subject `{0}`")]
+    InvalidSubject(String),
+}
+
+// 检查订阅的subject是否合法
+// 每一个token都不能为空, 通配符只能单独作为一个token, 并且 > 只能是最后一个token
+pub(super) fn is_valid_subject(subject: &str) -> bool {
+    let mut tokens = subject.split('.').peekable();
+
+    while let Some(token) = tokens.next() {
+        if token.is_empty() || token.contains(char::is_whitespace) {
+            return false;
+        }
 
-// 作为前缀树的缓存, 使用lru策略
+        match token {
+            TOKEN_WILDCARD => {}
+            FULL_WILDCARD => {
+                if tokens.peek().is_some() {
+                    return false;
+                }
+            }
+            _ => {
+                if token.contains(TOKEN_WILDCARD) || token.contains(FULL_WILDCARD) {
+                    return false;
+                }
+            }
+        }
+    }
+    true
+}
+
+// 发布的subject不能带有通配符
+pub(super) fn is_valid_literal_subject(subject: &str) -> bool {
+    is_valid_subject(subject)
+        && subject
+            .split('.')
+            .all(|token| token != TOKEN_WILDCARD && token != FULL_WILDCARD)
+}
+
+// 订阅的subject(可以带通配符)是否匹配发布的subject
+fn is_subject_match(pattern: &str, subject: &str) -> bool {
+    let mut tokens = subject.split('.');
+    for token in pattern.split('.') {
+        match token {
+            FULL_WILDCARD => return tokens.next().is_some(),
+            TOKEN_WILDCARD => {
+                if tokens.next().is_none() {
+                    return false;
+                }
+            }
+            _ => {
+                if tokens.next() != Some(token) {
+                    return false;
+                }
+            }
+        }
+    }
+    tokens.next().is_none()
+}
+
+// subject(也可以带通配符)能匹配到的所有subject, pattern是不是都能匹配到
+// subject没有通配符的时候和is_subject_match一样
+pub(super) fn is_subset_match(pattern: &str, subject: &str) -> bool {
+    let mut tokens = subject.split('.');
+    for token in pattern.split('.') {
+        match (token, tokens.next()) {
+            (_, None) => return false,
+            (FULL_WILDCARD, Some(_)) => return true,
+            (_, Some(FULL_WILDCARD)) => return false,
+            (TOKEN_WILDCARD, Some(_)) => {}
+            (_, Some(TOKEN_WILDCARD)) => return false,
+            (token, Some(other)) => {
+                if token != other {
+                    return false;
+                }
+            }
+        }
+    }
+    tokens.next().is_none()
+}
+
+// 两个subject(都可以带通配符)能不能匹配到同一个subject
+pub(super) fn is_subject_overlap(left: &str, right: &str) -> bool {
+    let mut left = left.split('.');
+    let mut right = right.split('.');
+    loop {
+        match (left.next(), right.next()) {
+            (None, None) => return true,
+            (Some(FULL_WILDCARD), Some(_)) | (Some(_), Some(FULL_WILDCARD)) => return true,
+            (None, Some(_)) | (Some(_), None) => return false,
+            (Some(TOKEN_WILDCARD), Some(_)) | (Some(_), Some(TOKEN_WILDCARD)) => {}
+            (Some(left), Some(right)) => {
+                if left != right {
+                    return false;
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn sublist_valid_subject() {
+    assert!(is_valid_subject("foo"));
+    assert!(is_valid_subject("foo.bar"));
+    assert!(is_valid_subject("foo.*"));
+    assert!(is_valid_subject("*.bar.*"));
+    assert!(is_valid_subject("foo.>"));
+    assert!(is_valid_subject(">"));
+
+    assert!(!is_valid_subject(""));
+    assert!(!is_valid_subject("foo..bar"));
+    assert!(!is_valid_subject(".foo"));
+    assert!(!is_valid_subject("foo."));
+    assert!(!is_valid_subject("foo.*bar"));
+    assert!(!is_valid_subject("foo.b>"));
+    assert!(!is_valid_subject(">.foo"));
+    assert!(!is_valid_subject("foo.>.bar"));
+    assert!(!is_valid_subject("foo bar"));
+}
+
+#[test]
+fn sublist_valid_literal_subject() {
+    assert!(is_valid_literal_subject("foo"));
+    assert!(is_valid_literal_subject("foo.bar"));
+    assert!(is_valid_literal_subject("_INBOX.abc"));
+
+    assert!(!is_valid_literal_subject("foo.*"));
+    assert!(!is_valid_literal_subject("foo.>"));
+    assert!(!is_valid_literal_subject(">"));
+    assert!(!is_valid_literal_subject("foo..bar"));
+}
+
+#[test]
+fn sublist_subject_match() {
+    assert!(is_subject_match("foo.bar", "foo.bar"));
+    assert!(is_subject_match("foo.*", "foo.bar"));
+    assert!(is_subject_match("*.bar", "foo.bar"));
+    assert!(is_subject_match("foo.>", "foo.bar.baz"));
+    assert!(is_subject_match(">", "foo"));
+
+    assert!(!is_subject_match("foo.bar", "foo.baz"));
+    assert!(!is_subject_match("foo.*", "foo"));
+    assert!(!is_subject_match("foo.*", "foo.bar.baz"));
+    assert!(!is_subject_match("foo.>", "foo"));
+    assert!(!is_subject_match("foo.bar", "foo"));
+}
+
+#[test]
+fn sublist_subset_match() {
+    assert!(is_subset_match("foo.bar", "foo.bar"));
+    assert!(is_subset_match("foo.*", "foo.bar"));
+    assert!(is_subset_match("foo.*", "foo.*"));
+    assert!(is_subset_match("foo.>", "foo.*.baz"));
+    assert!(is_subset_match("foo.>", "foo.>"));
+    assert!(is_subset_match(">", "foo.>"));
+
+    assert!(!is_subset_match("foo.bar", "foo.*"));
+    assert!(!is_subset_match("foo.*", "foo.>"));
+    assert!(!is_subset_match("foo.*.baz", "foo.>"));
+    assert!(!is_subset_match("foo.>", "foo"));
+    assert!(!is_subset_match("foo.*", "foo.bar.baz"));
+}
+
+#[test]
+fn sublist_subject_overlap() {
+    assert!(is_subject_overlap("foo.bar", "foo.bar"));
+    assert!(is_subject_overlap("secret.*", ">"));
+    assert!(is_subject_overlap("secret.*", "*.key"));
+    assert!(is_subject_overlap("foo.*.baz", "foo.bar.*"));
+    assert!(is_subject_overlap("foo.>", "*.bar.baz"));
+
+    assert!(!is_subject_overlap("foo.bar", "foo.baz"));
+    assert!(!is_subject_overlap("secret.*", "*"));
+    assert!(!is_subject_overlap("secret.*", "*.key.id"));
+    assert!(!is_subject_overlap("foo.>", "foo"));
+    assert!(!is_subject_overlap("foo.*", "bar.>"));
+}
+
+// 前缀树每一层的节点, 找到的节点会移到最前面, 常用的节点查找得比较快
+// 这只是一个线性查找的Vec, 真正的匹配结果缓存在SubList里面
 #[derive(Debug)]
 struct Level<T> {
     inner: Vec<T>,
@@ -29,21 +224,23 @@ impl<T> Level<T> {
         })
     }
 
-    fn remove<F>(&mut self, condition: F)
+    fn remove<F>(&mut self, mut condition: F)
     where
         F: FnMut(&T) -> bool,
     {
-        if let Some(key) = self.inner.iter().position(condition) {
-            self.inner.remove(key);
-        }
+        self.inner.retain(|value| !condition(value));
     }
 
-    fn iter(&self) -> Iter<T> {
-        self.inner.iter()
+    // 和search不同, 这里不会调整顺序, 所以只需要借用
+    fn get<F>(&self, condition: F) -> Option<&T>
+    where
+        F: FnMut(&&T) -> bool,
+    {
+        self.inner.iter().find(condition)
     }
 
-    fn iter_mut(&mut self) -> IterMut<T> {
-        self.inner.iter_mut()
+    fn iter(&self) -> Iter<'_, T> {
+        self.inner.iter()
     }
 
     fn len(&self) -> usize {
@@ -62,11 +259,80 @@ fn sublist_level() {
     level.remove(|value| *value == 1);
 }
 
+// 在前缀树里面找到的订阅
+// 同一个subject的匹配结果会被缓存起来, 所以匹配的时候都是共享同一份
+#[derive(Debug, PartialEq)]
+struct Matches<T> {
+    subs: Vec<T>,
+    queues: Vec<(String, Vec<T>)>,
+}
+
+impl<T> Matches<T> {
+    fn new() -> Self {
+        Self {
+            subs: Vec::new(),
+            queues: Vec::new(),
+        }
+    }
+
+    fn extend_queue(&mut self, name: &str, members: &[T])
+    where
+        T: Clone,
+    {
+        // 不同的subject匹配到同一个队列组的话, 要合并成一个组
+        match self.queues.iter_mut().find(|(n, _)| n == name) {
+            Some((_, group)) => group.extend(members.iter().cloned()),
+            None => self.queues.push((name.to_string(), members.to_vec())),
+        }
+    }
+}
+
+// 匹配的结果
+// 普通的订阅全部都要发送, 队列组的订阅每个组只发送给其中一个
+#[derive(Debug, PartialEq)]
+pub(super) struct SubResult<T> {
+    matches: Arc<Matches<T>>,
+    cursor: usize,
+}
+
+impl<T> SubResult<T> {
+    fn new(matches: Arc<Matches<T>>, cursor: usize) -> Self {
+        Self { matches, cursor }
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.matches.subs.is_empty() && self.matches.queues.is_empty()
+    }
+
+    pub(super) fn get_subs(&self) -> &[T] {
+        &self.matches.subs
+    }
+
+    // 每个队列组按照轮询的方式挑出一个订阅,
+    // 被挑中的订阅不满足condition的话就顺延到下一个, condition返回true之后就不再调用
+    pub(super) fn pick_queue_subs<F>(&self, condition: F) -> Vec<&T>
+    where
+        F: Fn(&T) -> bool,
+    {
+        self.matches
+            .queues
+            .iter()
+            .filter_map(|(_, members)| {
+                let len: usize = members.len();
+                (0..len)
+                    .map(|offset| &members[(self.cursor + offset) % len])
+                    .find(|member| condition(member))
+            })
+            .collect()
+    }
+}
+
 struct Entry<T>
 where
     T: Debug,
 {
     inner: Vec<T>,
+    queues: Level<(String, Vec<T>)>,
     next_level: Level<(String, Entry<T>)>,
 }
 
@@ -77,6 +343,7 @@ where
     fn new() -> Self {
         Self {
             inner: Vec::new(),
+            queues: Level::new(),
             next_level: Level::new(),
         }
     }
@@ -87,76 +354,112 @@ where
             .map(|(_, entry)| &mut *entry)
     }
 
-    fn subscribe(&mut self, list: &mut Vec<String>, subscription: T) {
+    fn subscribe(&mut self, list: &mut Vec<String>, queue: Option<String>, subscription: T) {
         if list.is_empty() {
-            self.inner.push(subscription);
+            match queue {
+                Some(queue) => match self.queues.search(|(name, _)| *name == queue) {
+                    Some((_, members)) => members.push(subscription),
+                    None => self.queues.insert((queue, vec![subscription])),
+                },
+                None => self.inner.push(subscription),
+            }
         } else {
             let key: String = list.remove(0);
 
             match self.search_mut_entry(&key) {
-                Some(entry) => entry.subscribe(list, subscription),
+                Some(entry) => entry.subscribe(list, queue, subscription),
                 None => {
                     let mut entry: Entry<T> = Self::new();
-                    entry.subscribe(list, subscription);
+                    entry.subscribe(list, queue, subscription);
                     self.next_level.insert((key, entry));
                 }
             }
         }
     }
 
-    fn get_subscribe_item(&mut self, list: &mut Vec<String>) -> Option<&mut Vec<T>> {
-        if list.is_empty() {
-            Some(&mut self.inner)
-        } else {
-            let key: String = list.remove(0);
-
-            self.search_mut_entry(&key)
-                .and_then(|entry| entry.get_subscribe_item(list))
+    fn collect(&self, result: &mut Matches<T>)
+    where
+        T: Clone,
+    {
+        result.subs.extend(self.inner.iter().cloned());
+        for (name, members) in self.queues.iter() {
+            result.extend_queue(name, members);
         }
     }
 
-    fn remove_subscription<F>(&mut self, remove_condition: &F)
+    fn get_entry(&self, key: &str) -> Option<&Self> {
+        self.next_level
+            .get(|(k, _)| k == key)
+            .map(|(_, entry)| entry)
+    }
+
+    // 同一层里面要同时匹配普通的token, * 和 >
+    // 所以匹配的结果是这几条路径的并集
+    fn match_subject(&self, tokens: &[&str], result: &mut Matches<T>)
     where
-        F: Fn(&T) -> bool,
+        T: Clone,
     {
-        if let Some(position) = self.inner.iter().position(remove_condition) {
-            self.inner.remove(position);
-        }
-        if self.next_level.len() > 0 {
-            for (_, entry) in self.next_level.iter_mut() {
-                entry.remove_subscription(remove_condition);
+        match tokens.split_first() {
+            None => self.collect(result),
+            Some((token, rest)) => {
+                // 发布的subject本身带有通配符的话, 只按照通配符去匹配, 避免重复
+                if *token != TOKEN_WILDCARD && *token != FULL_WILDCARD {
+                    if let Some(entry) = self.get_entry(token) {
+                        entry.match_subject(rest, result);
+                    }
+                }
+                if let Some(entry) = self.get_entry(TOKEN_WILDCARD) {
+                    entry.match_subject(rest, result);
+                }
+                if let Some(entry) = self.get_entry(FULL_WILDCARD) {
+                    entry.collect(result);
+                }
             }
         }
     }
 
-    fn remove(&mut self, list: &mut Vec<String>) {
-        match list.len() {
-            0 => {}
-            1 => {
-                let key: String = list.remove(0);
-                self.next_level.remove(|(k, _)| k == &key);
+    // 只沿着subject的路径去删除, 不需要遍历整棵树
+    fn unsubscribe<F>(&mut self, list: &[&str], queue: Option<&str>, remove_condition: &F) -> bool
+    where
+        F: Fn(&T) -> bool,
+    {
+        match list.split_first() {
+            None => {
+                let members: Option<&mut Vec<T>> = match queue {
+                    Some(queue) => self
+                        .queues
+                        .search(|(name, _)| name == queue)
+                        .map(|(_, members)| members),
+                    None => Some(&mut self.inner),
+                };
+                let removed: bool = match members {
+                    Some(members) => match members.iter().position(remove_condition) {
+                        Some(position) => {
+                            members.remove(position);
+                            true
+                        }
+                        None => false,
+                    },
+                    None => false,
+                };
+                self.queues.remove(|(_, members)| members.is_empty());
+                removed
             }
-            _ => {
-                let key: String = list.remove(0);
-                if let Some(entry) = self.search_mut_entry(&key) {
-                    entry.remove(list);
-                }
+            Some((key, rest)) => {
+                let removed: bool = match self.search_mut_entry(key) {
+                    Some(entry) => entry.unsubscribe(rest, queue, remove_condition),
+                    None => false,
+                };
+                // 已经没有订阅的节点要删掉, 不然前缀树只会越来越大
+                self.next_level
+                    .remove(|(k, entry)| k == key && entry.is_empty());
+                removed
             }
         }
     }
 
-    fn total(&self) -> usize {
-        let mut sum: usize = 0;
-
-        if self.next_level.len() == 0 {
-            1
-        } else {
-            for item in self.next_level.iter() {
-                sum += item.1.total();
-            }
-
-            sum
-        }
+    fn is_empty(&self) -> bool {
+        self.inner.is_empty() && self.queues.len() == 0 && self.next_level.len() == 0
     }
 }
 
@@ -177,20 +480,19 @@ fn sub_entry() {
 
     for item in 0..3 {
         let mut list = vec![String::from("hellow"), String::from("world")];
-        entry.subscribe(&mut list, item);
+        entry.subscribe(&mut list, None, item);
     }
 
-    let mut list = vec![String::from("hellow"), String::from("world")];
-    assert_eq!(
-        entry.get_subscribe_item(&mut list),
-        Some(&mut vec![0, 1, 2])
-    );
+    let mut result = Matches::new();
+    entry.match_subject(&["hellow", "world"], &mut result);
+    assert_eq!(result.subs, &[0, 1, 2]);
 
     let fnc: fn(&usize) -> bool = |item| *item == 1usize;
-    entry.remove_subscription(&fnc);
+    assert!(entry.unsubscribe(&["hellow", "world"], None, &fnc));
 
-    let mut list = vec![String::from("hellow"), String::from("world")];
-    assert_eq!(entry.get_subscribe_item(&mut list), Some(&mut vec![0, 2]));
+    let mut result = Matches::new();
+    entry.match_subject(&["hellow", "world"], &mut result);
+    assert_eq!(result.subs, &[0, 2]);
 }
 
 // 用前缀树做的订阅列表
@@ -200,6 +502,14 @@ where
     T: Clone + Debug,
 {
     root: Entry<T>,
+    // 队列组轮询的位置
+    queue_cursor: AtomicUsize,
+    // 发布的subject到匹配结果的缓存, 订阅和取消订阅的时候删掉受影响的subject
+    // 匹配的时候只持有订阅列表的读锁, 所以缓存自己还要一把锁
+    cache: RwLock<HashMap<String, Arc<Matches<T>>>>,
+    // 缓存的命中和未命中次数, 每个账户有自己的订阅列表, 所以分开统计
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
 }
 
 impl<T> SubList<T>
@@ -207,58 +517,266 @@ where
     T: Clone + Debug,
 {
     pub(super) fn new() -> Self {
-        Self { root: Entry::new() }
+        Self {
+            root: Entry::new(),
+            queue_cursor: AtomicUsize::new(0),
+            cache: RwLock::new(HashMap::new()),
+            cache_hits: AtomicU64::new(0),
+            cache_misses: AtomicU64::new(0),
+        }
     }
 
-    pub(super) fn subscribe(&mut self, sub: String, subscription: T) {
-        self.root.subscribe(&mut Self::split(sub), subscription);
+    pub(super) fn subscribe(
+        &mut self,
+        sub: String,
+        queue: Option<String>,
+        subscription: T,
+    ) -> Result<(), Error> {
+        if !is_valid_subject(&sub) {
+            return Err(Error::InvalidSubject(sub));
+        }
+        self.invalidate(&sub);
+        self.root.subscribe(&mut Self::split(sub), queue, subscription);
+        Ok(())
     }
 
-    pub(super) fn get_subscribe_item(&mut self, sub: String) -> Option<&mut Vec<T>> {
-        self.root.get_subscribe_item(&mut Self::split(sub))
+    // 返回所有匹配subject的订阅, 包括通配符的订阅
+    pub(super) fn match_subject(&self, subject: &str) -> SubResult<T> {
+        let cursor: usize = self.queue_cursor.fetch_add(1, Ordering::Relaxed);
+        if let Some(matches) = self.cache.read().unwrap_or_else(PoisonError::into_inner).get(subject) {
+            self.cache_hits.fetch_add(1, Ordering::Relaxed);
+            return SubResult::new(matches.clone(), cursor);
+        }
+        self.cache_misses.fetch_add(1, Ordering::Relaxed);
+
+        let tokens: Vec<&str> = subject.split('.').collect();
+        let mut matches: Matches<T> = Matches::new();
+        self.root.match_subject(&tokens, &mut matches);
+        let matches: Arc<Matches<T>> = Arc::new(matches);
+
+        // 带通配符的subject不好判断受哪些订阅影响, 所以不缓存
+        if is_valid_literal_subject(subject) {
+            let mut cache = self.cache.write().unwrap_or_else(PoisonError::into_inner);
+            if cache.len() >= CACHE_MAX {
+                debug!(
+                    "sub list cache full, hits {} misses {}",
+                    self.get_cache_hits(),
+                    self.get_cache_misses()
+                );
+                let removed: Vec<String> = cache.keys().take(CACHE_SWEEP).cloned().collect();
+                for key in removed {
+                    cache.remove(&key);
+                }
+            }
+            cache.insert(subject.to_string(), matches.clone());
+        }
+        SubResult::new(matches, cursor)
     }
 
-    pub(super) fn remove_subscription<F>(&mut self, remove_condition: F)
+    // 删除subject下满足条件的一个订阅, 返回是否删除成功
+    pub(super) fn unsubscribe<F>(&mut self, sub: &str, queue: Option<&str>, remove_condition: F) -> bool
     where
         F: Fn(&T) -> bool,
     {
-        self.root.remove_subscription(&remove_condition);
+        let list: Vec<&str> = sub.split('.').collect();
+        let removed: bool = self.root.unsubscribe(&list, queue, &remove_condition);
+        if removed {
+            self.invalidate(sub);
+        }
+        removed
     }
 
-    pub(super) fn remove(&mut self, sub: String) {
-        self.root.remove(&mut Self::split(sub))
+    // 匹配结果缓存的命中和未命中次数, 用来观察缓存的效果
+    pub(super) fn get_cache_hits(&self) -> u64 {
+        self.cache_hits.load(Ordering::Relaxed)
     }
 
-    fn split(key: String) -> Vec<String> {
-        key.split('.').map(|item| item.to_string()).collect()
+    pub(super) fn get_cache_misses(&self) -> u64 {
+        self.cache_misses.load(Ordering::Relaxed)
+    }
+
+    // 删掉会被这个订阅影响的缓存
+    fn invalidate(&mut self, sub: &str) {
+        self.cache
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|subject, _| !is_subject_match(sub, subject));
     }
 
-    pub fn total(&self) -> usize {
-        self.root.total()
+    fn split(key: String) -> Vec<String> {
+        key.split('.').map(|item| item.to_string()).collect()
     }
 }
 
 #[test]
 fn test_trie() {
-    use futures::executor::block_on;
     let mut sublist: SubList<usize> = SubList::new();
 
     let mut sub = Vec::new();
     for item in 0..100usize {
         sub.push(item);
-        sublist.subscribe(String::from("hello.world.fuck"), item);
+        sublist
+            .subscribe(String::from("hello.world.fuck"), None, item)
+            .unwrap();
     }
 
-    assert_eq!(
-        sublist.get_subscribe_item(String::from("hello.world.fuck")),
-        Some(&mut sub)
-    );
+    assert_eq!(sublist.match_subject("hello.world.fuck").get_subs(), &sub[..]);
 
-    sublist.remove_subscription(|item| *item == 50);
+    assert!(sublist.unsubscribe("hello.world.fuck", None, |item| *item == 50));
     sub.remove(50);
 
+    assert_eq!(sublist.match_subject("hello.world.fuck").get_subs(), &sub[..]);
+}
+
+#[test]
+fn test_trie_wildcard() {
+    let mut sublist: SubList<usize> = SubList::new();
+
+    sublist.subscribe(String::from("orders.created"), None, 0).unwrap();
+    sublist.subscribe(String::from("orders.*"), None, 1).unwrap();
+    sublist.subscribe(String::from("orders.>"), None, 2).unwrap();
+    sublist.subscribe(String::from("*.created"), None, 3).unwrap();
+    sublist.subscribe(String::from(">"), None, 4).unwrap();
+    sublist.subscribe(String::from("orders.*.eu"), None, 5).unwrap();
+
+    let mut result = sublist.match_subject("orders.created").get_subs().to_vec();
+    result.sort();
+    assert_eq!(result, vec![0, 1, 2, 3, 4]);
+
+    // > 至少要匹配一个token
+    let mut result = sublist.match_subject("orders").get_subs().to_vec();
+    result.sort();
+    assert_eq!(result, vec![4]);
+
+    let mut result = sublist.match_subject("orders.created.eu").get_subs().to_vec();
+    result.sort();
+    assert_eq!(result, vec![2, 4, 5]);
+
+    let mut result = sublist.match_subject("users.created").get_subs().to_vec();
+    result.sort();
+    assert_eq!(result, vec![3, 4]);
+
+    assert!(sublist.unsubscribe(">", None, |item| *item == 4));
+    assert!(sublist.match_subject("users.deleted").is_empty());
+}
+
+#[test]
+fn test_trie_invalid_subject() {
+    let mut sublist: SubList<usize> = SubList::new();
+
     assert_eq!(
-        sublist.get_subscribe_item(String::from("hello.world.fuck")),
-        Some(&mut sub)
+        sublist.subscribe(String::from("foo.*bar"), None, 0),
+        Err(Error::InvalidSubject(String::from("foo.*bar")))
     );
+    assert_eq!(
+        sublist.subscribe(String::from(">.foo"), None, 0),
+        Err(Error::InvalidSubject(String::from(">.foo")))
+    );
+    assert!(sublist.match_subject("foo.bar").is_empty());
+}
+
+#[test]
+fn test_trie_queue() {
+    let mut sublist: SubList<usize> = SubList::new();
+
+    sublist.subscribe(String::from("jobs.*"), None, 0).unwrap();
+    for item in 1..4 {
+        sublist
+            .subscribe(String::from("jobs.run"), Some(String::from("workers")), item)
+            .unwrap();
+    }
+    sublist
+        .subscribe(String::from("jobs.>"), Some(String::from("workers")), 4)
+        .unwrap();
+    sublist
+        .subscribe(String::from("jobs.run"), Some(String::from("audit")), 5)
+        .unwrap();
+
+    // 普通订阅每次都能收到, 每个队列组每次只有一个能收到, 并且轮流收到
+    let mut picked: Vec<usize> = Vec::new();
+    for _ in 0..8 {
+        let result = sublist.match_subject("jobs.run");
+        assert_eq!(result.get_subs(), &[0]);
+
+        let queue_subs = result.pick_queue_subs(|_| true);
+        assert_eq!(queue_subs.len(), 2);
+        assert!(queue_subs.contains(&&5));
+        picked.extend(queue_subs.into_iter().filter(|item| **item != 5));
+    }
+    for item in 1..5 {
+        assert_eq!(picked.iter().filter(|value| **value == item).count(), 2);
+    }
+
+    // 不满足条件的订阅会被跳过
+    let result = sublist.match_subject("jobs.run");
+    let queue_subs = result.pick_queue_subs(|item| *item != 5 && *item % 2 == 0);
+    assert_eq!(queue_subs.len(), 1);
+    assert_eq!(*queue_subs[0] % 2, 0);
+
+    assert!(sublist.unsubscribe("jobs.run", Some("audit"), |item| *item == 5));
+    let result = sublist.match_subject("jobs.run");
+    assert_eq!(result.pick_queue_subs(|_| true).len(), 1);
+}
+
+#[test]
+fn test_trie_unsubscribe() {
+    let mut sublist: SubList<(usize, usize)> = SubList::new();
+
+    // (client_id, sid)
+    sublist.subscribe(String::from("foo.bar"), None, (0, 1)).unwrap();
+    sublist.subscribe(String::from("foo.bar"), None, (1, 1)).unwrap();
+    sublist
+        .subscribe(String::from("foo.*"), Some(String::from("q")), (0, 2))
+        .unwrap();
+
+    // 同样的sid, 只删除自己连接的
+    assert!(sublist.unsubscribe("foo.bar", None, |item| *item == (1, 1)));
+    assert!(!sublist.unsubscribe("foo.bar", None, |item| *item == (1, 1)));
+    assert_eq!(sublist.match_subject("foo.bar").get_subs(), &[(0, 1)]);
+
+    // 队列组要给出组名
+    assert!(!sublist.unsubscribe("foo.*", None, |item| *item == (0, 2)));
+    assert!(sublist.unsubscribe("foo.*", Some("q"), |item| *item == (0, 2)));
+    assert!(sublist.unsubscribe("foo.bar", None, |item| *item == (0, 1)));
+    assert!(sublist.match_subject("foo.bar").is_empty());
+
+    // 空的节点都被删掉了
+    assert!(sublist.root.is_empty());
+}
+
+#[test]
+fn test_trie_cache() {
+    let mut sublist: SubList<usize> = SubList::new();
+    sublist.subscribe(String::from("foo.bar"), None, 0).unwrap();
+
+    assert_eq!(sublist.match_subject("foo.bar").get_subs(), &[0]);
+    assert_eq!(sublist.match_subject("foo.bar").get_subs(), &[0]);
+    assert!(sublist.match_subject("other.bar").is_empty());
+    assert_eq!(sublist.get_cache_hits(), 1);
+    assert_eq!(sublist.get_cache_misses(), 2);
+    assert!(sublist.cache.read().unwrap().contains_key("foo.bar"));
+    assert!(sublist.cache.read().unwrap().contains_key("other.bar"));
+
+    // 只删掉受影响的subject
+    sublist.subscribe(String::from("foo.*"), None, 1).unwrap();
+    assert!(!sublist.cache.read().unwrap().contains_key("foo.bar"));
+    assert!(sublist.cache.read().unwrap().contains_key("other.bar"));
+    assert_eq!(sublist.match_subject("foo.bar").get_subs(), &[0, 1]);
+
+    assert!(sublist.unsubscribe("foo.*", None, |item| *item == 1));
+    assert_eq!(sublist.match_subject("foo.bar").get_subs(), &[0]);
+
+    // 没有删除成功的话不影响缓存
+    assert!(!sublist.unsubscribe("foo.bar", None, |item| *item == 1));
+    assert!(sublist.cache.read().unwrap().contains_key("foo.bar"));
+
+    // 带通配符的subject不缓存
+    sublist.match_subject("foo.*");
+    assert!(!sublist.cache.read().unwrap().contains_key("foo.*"));
+
+    // 缓存的数量是有上限的
+    for item in 0..CACHE_MAX * 2 {
+        sublist.match_subject(&format!("foo.{}", item));
+    }
+    assert!(sublist.cache.read().unwrap().len() <= CACHE_MAX);
 }
@@ -1,79 +1,110 @@
-// use super::encode::Msg;
-// use super::write_stream::WriteStream;
-// use async_spmc::Receiver as SubReceiver;
-// use log::error;
-// use std::sync::Arc;
-// use tokio::select;
-// use tokio::sync::mpsc::UnboundedReceiver;
-// use tokio::sync::Mutex;
+use super::permissions::Responses;
+use super::sub_list::SubList;
+use super::write_stream::Outbound;
+use bytes::Bytes;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 
-// #[derive(Debug)]
-// pub(super) enum MessageEvent {
-//     UnSub(Option<u32>),
-// }
+// 一个订阅
+// 订阅列表里面保存的是Arc<Subscription>, 匹配的时候复制出来的也是同一个订阅,
+// 所以已经发送的数量要用原子变量来记录
+#[derive(Debug)]
+pub(super) struct Subscription {
+    // 订阅所在连接的发送缓冲区
+    outbound: Arc<Outbound>,
+    // 发布者设置了no_echo的话, 用来跳过发布者自己的订阅
+    client_id: usize,
+    // 每次发送消息都要写入sid, 保存成Bytes就不用每次复制
+    sid: Bytes,
+    subject: String,
+    queue: Option<String>,
+    // 连接在CONNECT的时候是否声明支持header, 不支持的话发送消息的时候要去掉header
+    headers: bool,
+    // UNSUB <sid> <max_msgs> 设置的最大发送数量, 0 表示不限制
+    max_msgs: AtomicU64,
+    delivered: AtomicU64,
+    // 用户有回复权限的话, 记录发送给这个连接的reply
+    responses: Option<Arc<Responses>>,
+}
 
-// #[derive(Debug)]
-// pub(super) struct SubStruct {
-//     stream: Arc<Mutex<WriteStream>>,
-//     sub_receiver: SubReceiver<(String, Option<String>, String)>,
-//     receiver: UnboundedReceiver<MessageEvent>,
-//     sid: String,
-//     max_message: Option<u32>,
-// }
+impl Subscription {
+    pub(super) fn new(
+        outbound: Arc<Outbound>,
+        client_id: usize,
+        sid: String,
+        subject: String,
+        queue: Option<String>,
+        headers: bool,
+    ) -> Self {
+        Self {
+            outbound,
+            client_id,
+            sid: Bytes::from(sid),
+            subject,
+            queue,
+            headers,
+            max_msgs: AtomicU64::new(0),
+            delivered: AtomicU64::new(0),
+            responses: None,
+        }
+    }
 
-// impl SubStruct {
-//     pub(super) fn new(
-//         stream: Arc<Mutex<WriteStream>>,
-//         sub_receiver: SubReceiver<(String, Option<String>, String)>,
-//         receiver: UnboundedReceiver<MessageEvent>,
-//         sid: String,
-//     ) -> Self {
-//         Self {
-//             stream,
-//             sub_receiver,
-//             receiver,
-//             sid,
-//             max_message: None,
-//         }
-//     }
+    pub(super) fn set_responses(mut self, responses: Option<Arc<Responses>>) -> Self {
+        self.responses = responses;
+        self
+    }
 
-//     pub(super) async fn run(mut self) {
-//         'run: loop {
-//             select! {
-//                 Some(event) =self.receiver.recv() => {
-//                     match event {
-//                         MessageEvent::UnSub(max_message) => {
-//                             if max_message.is_none() {
-//                                 break 'run;
-//                             } else {
-//                                 self.max_message = max_message;
-//                             }
-//                         }
-//                     }
-//                 }
-//                 try_iter = self.sub_receiver.recv_iter() => {
-//                     let mut stream = self.stream.lock().await;
-//                     for (content, reply_to, subject) in try_iter {
+    pub(super) fn get_outbound(&self) -> &Arc<Outbound> {
+        &self.outbound
+    }
 
-//                         if let Err(e) = stream.write(
-//                             Msg::new(subject.as_str(), self.sid.as_str(), reply_to.as_deref(), content.as_str())
-//                                 .format()
-//                                 .as_bytes()
-//                         ).await {
-//                             error!("{:?}", e);
-//                             break 'run;
-//                         }
+    pub(super) fn get_sid(&self) -> &Bytes {
+        &self.sid
+    }
 
-//                         if let Some(count) = self.max_message.as_mut() {
-//                             *count += 1;
+    pub(super) fn get_queue(&self) -> Option<&str> {
+        self.queue.as_deref()
+    }
 
-//                             if *count == 0 {
-//                                 break 'run;
-//                             }
-//                         }
-//                     }
-//                 }
-//             }
-//         }
-//     }
-// }
+    pub(super) fn get_client_id(&self) -> usize {
+        self.client_id
+    }
+
+    pub(super) fn supports_headers(&self) -> bool {
+        self.headers
+    }
+
+    // 在发送消息之前记录, 订阅者收到之后马上回复也不会被拒绝
+    pub(super) fn add_reply(&self, reply_to: &str) {
+        if let Some(responses) = &self.responses {
+            responses.add(reply_to);
+        }
+    }
+
+    // 沿着自己的subject从订阅列表里面删除自己
+    // 自动取消订阅之后sid可以重新使用, 所以要按照是不是同一个订阅来删除, 不能按照sid
+    pub(super) fn unsubscribe_from(&self, sub_list: &mut SubList<Arc<Subscription>>) -> bool {
+        sub_list.unsubscribe(&self.subject, self.get_queue(), |item| {
+            std::ptr::eq(Arc::as_ptr(item), self)
+        })
+    }
+
+    // 设置最大发送数量, 已经发送过的消息也算在里面
+    // 返回true表示已经发送够了, 需要马上取消订阅
+    pub(super) fn set_max_msgs(&self, max_msgs: u64) -> bool {
+        self.max_msgs.store(max_msgs, Ordering::Release);
+        self.is_exhausted()
+    }
+
+    // 发送之前先占一个名额, 返回false表示已经发送够了, 不能再发送
+    pub(super) fn take(&self) -> bool {
+        let delivered: u64 = self.delivered.fetch_add(1, Ordering::AcqRel) + 1;
+        let max_msgs: u64 = self.max_msgs.load(Ordering::Acquire);
+        max_msgs == 0 || delivered <= max_msgs
+    }
+
+    pub(super) fn is_exhausted(&self) -> bool {
+        let max_msgs: u64 = self.max_msgs.load(Ordering::Acquire);
+        max_msgs != 0 && self.delivered.load(Ordering::Acquire) >= max_msgs
+    }
+}
@@ -0,0 +1,346 @@
+use crate::config::TlsConfig;
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::io::{Error as IoError, ErrorKind, IoSlice, Result as IoResult};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tokio_rustls::rustls::crypto::{ring, CryptoProvider};
+use tokio_rustls::rustls::pki_types::pem::{Error as PemError, PemObject};
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::{VerifierBuilderError, WebPkiClientVerifier};
+use tokio_rustls::rustls::{Error as RustlsError, RootCertStore, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use x509_parser::extensions::GeneralName;
+use x509_parser::objects::{oid2abbrev, oid_registry};
+use x509_parser::parse_x509_certificate;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("io error `{0}`")]
+    Io(#[from] IoError),
+
+    #[error("pem error `{0}`")]
+    Pem(#[from] PemError),
+
+    #[error("rustls error `{0}`")]
+    Rustls(#[from] RustlsError),
+
+    #[error("client verifier error `{0}`")]
+    Verifier(#[from] VerifierBuilderError),
+
+    #[error("verify client certificates without ca_file")]
+    MissingCa,
+
+    #[error("invalid tls timeout `{0}`")]
+    InvalidTimeout(f64),
+}
+
+// 客户端的连接, 握手之后读写都要经过TLS
+#[derive(Debug)]
+pub(super) enum Socket {
+    Tcp(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl AsyncRead for Socket {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<IoResult<()>> {
+        match self.get_mut() {
+            Socket::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Socket::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Socket {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<IoResult<usize>> {
+        match self.get_mut() {
+            Socket::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Socket::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<IoResult<usize>> {
+        match self.get_mut() {
+            Socket::Tcp(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+            Socket::Tls(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            Socket::Tcp(stream) => stream.is_write_vectored(),
+            Socket::Tls(stream) => stream.is_write_vectored(),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        match self.get_mut() {
+            Socket::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Socket::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        match self.get_mut() {
+            Socket::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Socket::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+impl Socket {
+    // 客户端证书里面可以用来对应用户的身份
+    // 和nats一样先是SAN里面的email, 然后是RFC 2253格式的subject, 例如 CN=client,O=beaver
+    pub(super) fn get_peer_identities(&self) -> Vec<String> {
+        let cert: &CertificateDer<'_> = match self {
+            Socket::Tls(stream) => match stream.get_ref().1.peer_certificates() {
+                Some([cert, ..]) => cert,
+                _ => return Vec::new(),
+            },
+            Socket::Tcp(_) => return Vec::new(),
+        };
+        let cert = match parse_x509_certificate(cert) {
+            Ok((_, cert)) => cert,
+            Err(_) => return Vec::new(),
+        };
+
+        let mut identities: Vec<String> = Vec::new();
+        if let Ok(Some(san)) = cert.subject_alternative_name() {
+            for name in &san.value.general_names {
+                if let GeneralName::RFC822Name(email) = name {
+                    identities.push(email.to_string());
+                }
+            }
+        }
+
+        // RFC 2253是从最后一个RDN开始写的
+        let mut subject: Vec<String> = cert
+            .subject()
+            .iter_rdn()
+            .flat_map(|rdn| rdn.iter())
+            .filter_map(|attr| {
+                let name: &str = oid2abbrev(attr.attr_type(), oid_registry()).ok()?;
+                Some(format!("{}={}", name, attr.as_str().ok()?))
+            })
+            .collect();
+        subject.reverse();
+        if !subject.is_empty() {
+            identities.push(subject.join(","));
+        }
+        identities
+    }
+}
+
+// 服务端的TLS配置, 启动的时候加载一次证书, 所有连接共用
+#[derive(Clone)]
+pub(super) struct Tls {
+    acceptor: TlsAcceptor,
+    tls_first: bool,
+    verify_and_map: bool,
+    timeout: Duration,
+}
+
+impl Debug for Tls {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.debug_struct("Tls")
+            .field("tls_first", &self.tls_first)
+            .field("verify_and_map", &self.verify_and_map)
+            .field("timeout", &self.timeout)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Tls {
+    pub(super) fn new(config: &TlsConfig) -> Result<Self, Error> {
+        if config.is_verify() && config.get_ca_file().is_none() {
+            return Err(Error::MissingCa);
+        }
+        // 负数, NaN或者太大的超时时间启动的时候就报错
+        let timeout: Duration = Duration::try_from_secs_f64(config.get_timeout())
+            .map_err(|_| Error::InvalidTimeout(config.get_timeout()))?;
+        // 只编译了ring, 不依赖进程默认的加密实现
+        let provider: Arc<CryptoProvider> = Arc::new(ring::default_provider());
+
+        let certs: Vec<CertificateDer<'static>> =
+            CertificateDer::pem_file_iter(config.get_cert_file())?.collect::<Result<_, _>>()?;
+        let key: PrivateKeyDer<'static> = PrivateKeyDer::from_pem_file(config.get_key_file())?;
+
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?;
+        let server_config: ServerConfig = match config.get_ca_file() {
+            // 配置了CA的话, 客户端发送的证书必须是这个CA签发的
+            // 没有要求verify的话, 客户端也可以不发送证书
+            Some(ca_file) => {
+                let mut roots: RootCertStore = RootCertStore::empty();
+                for cert in CertificateDer::pem_file_iter(ca_file)? {
+                    roots.add(cert?)?;
+                }
+                let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+                let verifier = if config.is_verify() {
+                    verifier.build()?
+                } else {
+                    verifier.allow_unauthenticated().build()?
+                };
+                builder
+                    .with_client_cert_verifier(verifier)
+                    .with_single_cert(certs, key)?
+            }
+            None => builder.with_no_client_auth().with_single_cert(certs, key)?,
+        };
+
+        Ok(Self {
+            acceptor: TlsAcceptor::from(Arc::new(server_config)),
+            tls_first: config.is_tls_first(),
+            verify_and_map: config.is_verify_and_map(),
+            timeout,
+        })
+    }
+
+    pub(super) fn is_tls_first(&self) -> bool {
+        self.tls_first
+    }
+
+    pub(super) fn is_verify_and_map(&self) -> bool {
+        self.verify_and_map
+    }
+
+    // 客户端一直不握手的话, 不能一直占用连接
+    pub(super) async fn accept(&self, socket: TcpStream) -> IoResult<Socket> {
+        match timeout(self.timeout, self.acceptor.accept(socket)).await {
+            Ok(stream) => Ok(Socket::Tls(Box::new(stream?))),
+            Err(_) => Err(IoError::new(ErrorKind::TimedOut, "tls handshake timeout")),
+        }
+    }
+}
+
+// 测试用的证书, 写到临时目录里面再按照配置文件的方式加载
+// 服务端用自签名证书, 客户端证书由另外一个CA签发, subject是 CN=client,O=beaver, email是 client@beaver.io
+// options是额外的TLS配置, 返回服务端的配置, 不带证书的客户端, 带证书的客户端
+#[cfg(test)]
+pub(super) fn test_tls(options: &str) -> (Tls, tokio_rustls::TlsConnector, tokio_rustls::TlsConnector) {
+    use rcgen::{
+        BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, Issuer, KeyPair,
+        SanType,
+    };
+    use std::convert::{TryFrom, TryInto};
+    use std::fs::write;
+    use tokio_rustls::rustls::ClientConfig;
+
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+
+    let ca_key: KeyPair = KeyPair::generate().unwrap();
+    let mut ca_params: CertificateParams = CertificateParams::new(Vec::new()).unwrap();
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    ca_params.distinguished_name.push(DnType::CommonName, "beaver ca");
+    let ca = ca_params.self_signed(&ca_key).unwrap();
+
+    let client_key: KeyPair = KeyPair::generate().unwrap();
+    let mut client_params: CertificateParams = CertificateParams::new(Vec::new()).unwrap();
+    client_params.distinguished_name = rcgen::DistinguishedName::new();
+    client_params.distinguished_name.push(DnType::OrganizationName, "beaver");
+    client_params.distinguished_name.push(DnType::CommonName, "client");
+    client_params
+        .subject_alt_names
+        .push(SanType::Rfc822Name("client@beaver.io".try_into().unwrap()));
+    client_params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+    let client = client_params
+        .signed_by(&client_key, &Issuer::new(ca_params, ca_key))
+        .unwrap();
+
+    let dir = std::env::temp_dir();
+    let name: String = uuid::Uuid::new_v4().to_simple().to_string();
+    let cert_file = dir.join(format!("{}-cert.pem", name));
+    let key_file = dir.join(format!("{}-key.pem", name));
+    let ca_file = dir.join(format!("{}-ca.pem", name));
+    write(&cert_file, cert.cert.pem()).unwrap();
+    write(&key_file, cert.signing_key.serialize_pem()).unwrap();
+    write(&ca_file, ca.pem()).unwrap();
+
+    let config: TlsConfig = toml::from_str(&format!(
+        "cert_file = {:?}\nkey_file = {:?}\nca_file = {:?}\n{}",
+        cert_file, key_file, ca_file, options
+    ))
+    .unwrap();
+    let tls: Result<Tls, Error> = Tls::new(&config);
+    // 证书在Tls::new的时候已经加载了, 文件可以马上删掉
+    for file in &[cert_file, key_file, ca_file] {
+        std::fs::remove_file(file).unwrap();
+    }
+    let tls: Tls = tls.unwrap();
+
+    let mut roots: RootCertStore = RootCertStore::empty();
+    roots.add(cert.cert.der().clone()).unwrap();
+    let builder = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots);
+    let connector = builder.clone().with_no_client_auth();
+    let cert_connector = builder
+        .with_client_auth_cert(
+            vec![client.der().clone()],
+            PrivateKeyDer::try_from(client_key.serialize_der()).unwrap(),
+        )
+        .unwrap();
+
+    (
+        tls,
+        tokio_rustls::TlsConnector::from(Arc::new(connector)),
+        tokio_rustls::TlsConnector::from(Arc::new(cert_connector)),
+    )
+}
+
+#[test]
+fn tls_load_config() {
+    let (tls, _, _) = test_tls("tls_first = true\ntimeout = 0.5\nverify_and_map = true");
+    assert!(tls.is_tls_first());
+    assert!(tls.is_verify_and_map());
+    assert_eq!(tls.timeout, Duration::from_millis(500));
+
+    let (tls, _, _) = test_tls("");
+    assert!(!tls.is_tls_first());
+    assert!(!tls.is_verify_and_map());
+    assert_eq!(tls.timeout, Duration::from_secs(2));
+
+    // 文件不存在
+    let config: TlsConfig =
+        toml::from_str("cert_file = \"/not/exists/cert.pem\"\nkey_file = \"/not/exists/key.pem\"")
+            .unwrap();
+    assert!(Tls::new(&config).is_err());
+
+    // 要求客户端证书的话必须配置CA
+    let config: TlsConfig = toml::from_str(
+        "cert_file = \"/not/exists/cert.pem\"\nkey_file = \"/not/exists/key.pem\"\nverify = true",
+    )
+    .unwrap();
+    match Tls::new(&config) {
+        Err(Error::MissingCa) => {}
+        _ => panic!("verify without ca_file"),
+    }
+
+    // 超时时间不合法
+    for timeout in &["-1.0", "nan", "inf"] {
+        let config: TlsConfig = toml::from_str(&format!(
+            "cert_file = \"/not/exists/cert.pem\"\nkey_file = \"/not/exists/key.pem\"\ntimeout = {}",
+            timeout
+        ))
+        .unwrap();
+        match Tls::new(&config) {
+            Err(Error::InvalidTimeout(_)) => {}
+            _ => panic!("invalid timeout {}", timeout),
+        }
+    }
+}
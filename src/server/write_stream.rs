@@ -1,63 +1,313 @@
-use log::{debug, error};
-use std::io::{Error as IoError, Result as IoResult};
-use std::net::SocketAddr;
-use std::ops::Drop;
+use super::encode::ResponseErr;
+use super::tls::Socket;
+use crate::global_static::STATS;
+use bytes::Bytes;
+use log::debug;
+use std::io::{Error as IoError, ErrorKind, IoSlice, Result as IoResult};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::future::Future;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
 use tokio::io::AsyncWriteExt;
-use tokio::io::{WriteHalf, BufWriter};
-use tokio::net::TcpStream;
-use tokio::spawn;
+use tokio::io::{BufWriter, WriteHalf};
+use tokio::sync::Notify;
+use tokio::time::error::Elapsed;
+use tokio::time::timeout;
 
 #[derive(Debug)]
 pub(super) struct WriteStream {
-    stream: BufWriter<WriteHalf<TcpStream>>,
-    ssl_required: bool,
-
-    // 是否回复
-    verbose: bool,
+    stream: BufWriter<WriteHalf<Socket>>,
 }
 
 impl WriteStream {
-    pub(super) fn new(stream: BufWriter<WriteHalf<TcpStream>>) -> Self {
+    pub(super) fn new(stream: BufWriter<WriteHalf<Socket>>) -> Self {
+        Self { stream }
+    }
+
+    // 一次系统调用写入多段数据, 没有写完的话从写到的位置继续
+    // 比较小的数据BufWriter会先合并到自己的缓冲区里面
+    pub(super) async fn write_vectored(&mut self, chunks: &[Bytes]) -> IoResult<()> {
+        let mut slices: Vec<IoSlice<'_>> = chunks.iter().map(|chunk| IoSlice::new(chunk)).collect();
+        let mut slices: &mut [IoSlice<'_>] = &mut slices;
+        while !slices.is_empty() {
+            let size: usize = self.stream.write_vectored(slices).await?;
+            if size == 0 {
+                return Err(IoError::from(ErrorKind::WriteZero));
+            }
+            IoSlice::advance_slices(&mut slices, size);
+        }
+        Ok(())
+    }
+
+    pub(super) async fn shutdown(&mut self) -> IoResult<()> {
+        self.stream.shutdown().await
+    }
+
+    pub(super) async fn flush(&mut self) -> IoResult<()> {
+        self.stream.flush().await
+    }
+}
+
+// 还没有发送的数据, 保存的是Bytes的引用, 同一个payload发给多个订阅者的时候不会复制
+#[derive(Debug, Default)]
+struct Pending {
+    chunks: Vec<Bytes>,
+    size: usize,
+}
+
+impl Pending {
+    fn push(&mut self, chunk: Bytes) {
+        self.size += chunk.len();
+        self.chunks.push(chunk);
+    }
+
+    fn clear(&mut self) {
+        self.chunks.clear();
+        self.size = 0;
+    }
+}
+
+// 一个连接的发送缓冲区
+// 发布消息的时候只需要把数据放进缓冲区, 然后通知这个连接的写入任务,
+// 写入任务会把缓冲区里面的数据一次性全部取出来写到socket里面, 这样多条消息就可以合并成一次写入,
+// 发布者也不需要等待订阅者的socket
+//
+// 缓冲区里面的数据加上正在写入的数据超过max_pending, 或者在write_deadline之内写不完的话,
+// 说明客户端读取的速度跟不上, 作为慢消费者断开连接, write_deadline为0表示写入不限时间
+#[derive(Debug)]
+pub(super) struct Outbound {
+    buffer: Mutex<Pending>,
+    // 写入任务已经从缓冲区取出来, 还没有写完的数据大小, 只在持有缓冲区的锁的时候修改
+    in_flight: AtomicUsize,
+    notify: Notify,
+    closed: AtomicBool,
+    // 连接的读取任务等待关闭的通知
+    closed_notify: Notify,
+    slow_consumer: AtomicBool,
+    max_pending: usize,
+    write_deadline: Duration,
+}
+
+impl Outbound {
+    pub(super) fn new(max_pending: usize, write_deadline: Duration) -> Self {
         Self {
-            stream,
-            ssl_required: false,
-            verbose: true,
+            buffer: Mutex::new(Pending::default()),
+            in_flight: AtomicUsize::new(0),
+            notify: Notify::new(),
+            closed: AtomicBool::new(false),
+            closed_notify: Notify::new(),
+            slow_consumer: AtomicBool::new(false),
+            max_pending,
+            write_deadline,
         }
     }
 
-    pub(super) async fn write(&mut self, buff: &[u8]) -> IoResult<()> {
-        self.stream.write_all(buff).await
+    // 服务器自己的回复比较短, 直接复制一份
+    pub(super) fn write(&self, buff: &[u8]) -> IoResult<()> {
+        self.write_chunks(&[Bytes::copy_from_slice(buff)])
     }
 
-    pub(super) async fn send_ok(&mut self, buff: &[u8]) -> IoResult<()> {
-        debug!("verbose set {:?}", self.verbose);
-        if self.verbose {
-            self.write(buff).await?;
+    // 多段数据在同一次加锁里面放进缓冲区, 不会和其他发布者的数据交错
+    pub(super) fn write_chunks(&self, chunks: &[Bytes]) -> IoResult<()> {
+        if self.is_closed() {
+            return Err(IoError::from(ErrorKind::BrokenPipe));
+        }
+        {
+            let mut buffer = self.lock_buffer();
+            let size: usize = chunks.iter().map(|chunk| chunk.len()).sum();
+            if buffer.size + self.in_flight.load(Ordering::Acquire) + size > self.max_pending {
+                drop(buffer);
+                self.slow_consumer(true);
+                return Err(IoError::from(ErrorKind::WouldBlock));
+            }
+            for chunk in chunks.iter().filter(|chunk| !chunk.is_empty()) {
+                buffer.push(chunk.clone());
+            }
         }
+        self.notify.notify_one();
         Ok(())
     }
 
-    pub(super) async fn send_err(&mut self, buff: &[u8]) -> IoResult<()> {
-        debug!("verbose set {:?}", self.verbose);
-        if self.verbose {
-            self.stream.write_all(buff).await?;
+    // 关闭之后就不能再放数据进来, 写入任务把剩下的数据写完之后就会结束
+    pub(super) fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        self.notify.notify_one();
+        self.closed_notify.notify_one();
+    }
+
+    pub(super) fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+
+    // 等待关闭, 只有连接自己的读取任务会等待
+    // notify_one会保存通知, 所以在判断和等待之间关闭也不会漏掉
+    pub(super) async fn closed(&self) {
+        if !self.is_closed() {
+            self.closed_notify.notified().await;
         }
-        Ok(())
     }
 
-    pub(super) async fn shutdown(&mut self) -> IoResult<()> {
-        self.stream.shutdown().await
+    // 还没发送的数据已经没有意义了, 全部丢掉然后关闭
+    // 缓冲区里面都是完整的消息, send_err的时候在后面给客户端发送一个错误,
+    // 写入超时的时候socket上可能还有写了一半的消息, 不能再发送任何数据
+    fn slow_consumer(&self, send_err: bool) {
+        if !self.slow_consumer.swap(true, Ordering::AcqRel) {
+            debug!("slow consumer");
+            STATS.add_slow_consumer();
+
+            let mut buffer = self.lock_buffer();
+            buffer.clear();
+            if send_err {
+                buffer.push(Bytes::from_static(ResponseErr::slow_consumer()));
+            }
+        }
+        self.close();
     }
 
-    pub(super) fn set_ssl(&mut self, ssl_required: bool) {
-        self.ssl_required = ssl_required;
+    fn lock_buffer(&self) -> MutexGuard<'_, Pending> {
+        self.buffer.lock().unwrap_or_else(PoisonError::into_inner)
     }
 
-    pub(super) fn set_verbose(&mut self, verbose: bool) {
-        self.verbose = verbose;
+    async fn with_deadline<F: Future>(&self, future: F) -> Result<F::Output, Elapsed> {
+        if self.write_deadline.is_zero() {
+            return Ok(future.await);
+        }
+        timeout(self.write_deadline, future).await
     }
 
-    pub(super) async fn flush(&mut self) -> IoResult<()> {
-        self.stream.flush().await
+    // 连接的写入任务, 有数据的时候才会被唤醒
+    // notify_one在没有等待者的时候会保存一个通知, 所以不会漏掉唤醒
+    pub(super) async fn run(self: Arc<Self>, mut write_stream: WriteStream) {
+        // 和缓冲区交换, 可以重复利用两边已经分配好的内存
+        let mut pending: Pending = Pending::default();
+        loop {
+            {
+                let mut buffer = self.lock_buffer();
+                std::mem::swap(&mut *buffer, &mut pending);
+                self.in_flight.store(pending.size, Ordering::Release);
+            }
+
+            if pending.chunks.is_empty() {
+                if self.is_closed() {
+                    break;
+                }
+                self.notify.notified().await;
+                continue;
+            }
+
+            let result = self.with_deadline(async {
+                write_stream.write_vectored(&pending.chunks).await?;
+                write_stream.flush().await
+            })
+            .await;
+            pending.clear();
+            self.in_flight.store(0, Ordering::Release);
+            match result {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => {
+                    debug!("write error {:?}", e);
+                    self.close();
+                    break;
+                }
+                // 超时的时候不能再flush, BufWriter里面的数据也不要了, 直接丢掉socket
+                Err(_) => {
+                    self.slow_consumer(false);
+                    return;
+                }
+            }
+        }
+
+        // 关闭的时候BufWriter还要flush, 客户端不读取的话也会一直等待
+        match self.with_deadline(write_stream.shutdown()).await {
+            Ok(Err(e)) => debug!("shutdown error {:?}", e),
+            Err(_) => debug!("shutdown timeout"),
+            Ok(Ok(())) => {}
+        }
     }
 }
+
+#[tokio::test]
+async fn outbound_write_and_close() {
+    use tokio::io::{split, AsyncReadExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+    let (socket, _) = listener.accept().await.unwrap();
+    let (_read_half, write_half) = split(Socket::Tcp(socket));
+
+    // 写入任务启动之前放进去的数据也不会丢
+    let outbound: Arc<Outbound> = Arc::new(Outbound::new(1024, Duration::from_secs(1)));
+    outbound.write(b"PING\r\n").unwrap();
+    outbound
+        .write_chunks(&[
+            Bytes::from_static(b"MSG foo "),
+            Bytes::from_static(b"1"),
+            Bytes::from_static(b" 2\r\n"),
+            Bytes::new(),
+            Bytes::from_static(b"hi\r\n"),
+        ])
+        .unwrap();
+    let writer = tokio::spawn(
+        outbound
+            .clone()
+            .run(WriteStream::new(BufWriter::new(write_half))),
+    );
+    outbound.write(b"PONG\r\n").unwrap();
+
+    // 关闭之后不能再写入, 已经放进去的数据要写完才断开
+    outbound.close();
+    assert!(outbound.write(b"PING\r\n").is_err());
+    writer.await.unwrap();
+
+    let mut result: Vec<u8> = Vec::new();
+    client.read_to_end(&mut result).await.unwrap();
+    assert_eq!(result, b"PING\r\nMSG foo 1 2\r\nhi\r\nPONG\r\n");
+}
+
+#[test]
+fn outbound_max_pending_in_flight() {
+    let outbound: Outbound = Outbound::new(16, Duration::from_secs(1));
+    outbound.write(b"PING\r\nPING\r\n").unwrap();
+
+    // 写入任务取走的数据还没有写完的时候也要算在max_pending里面
+    {
+        let mut buffer = outbound.lock_buffer();
+        outbound.in_flight.store(buffer.size, Ordering::Release);
+        buffer.clear();
+    }
+    assert!(outbound.write(b"PING\r\n").is_err());
+    assert!(outbound.is_closed());
+}
+
+#[tokio::test]
+async fn outbound_no_write_deadline() {
+    use tokio::io::{split, AsyncReadExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+    let (socket, _) = listener.accept().await.unwrap();
+    let (_read_half, write_half) = split(Socket::Tcp(socket));
+
+    // write_deadline为0的时候写入不会超时, 客户端读得慢也不会被当作慢消费者
+    let payload: Bytes = Bytes::from(vec![b'a'; 8 * 1024 * 1024]);
+    let outbound: Arc<Outbound> = Arc::new(Outbound::new(16 * 1024 * 1024, Duration::ZERO));
+    outbound.write_chunks(std::slice::from_ref(&payload)).unwrap();
+    outbound.close();
+    let writer = tokio::spawn(
+        outbound
+            .clone()
+            .run(WriteStream::new(BufWriter::new(write_half))),
+    );
+
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let mut result: Vec<u8> = Vec::new();
+    timeout(Duration::from_secs(5), client.read_to_end(&mut result))
+        .await
+        .unwrap()
+        .unwrap();
+    writer.await.unwrap();
+    assert_eq!(result.len(), payload.len());
+    assert!(!outbound.slow_consumer.load(Ordering::Acquire));
+}